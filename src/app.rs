@@ -1,18 +1,28 @@
 // Defines your CLI interface using structopt
+use crate::directories::OnConflict;
+use crate::downloaders::SymlinkPolicy;
+use crate::placeholders::parse_var;
+use std::path::PathBuf;
+use structopt::clap::{AppSettings, ArgSettings};
 use structopt::StructOpt;
 
 /// Copies all or portions of a remote git repo.
 #[derive(StructOpt, Debug)]
-#[structopt(name = env!("CARGO_PKG_NAME"))]
+#[structopt(
+    name = env!("CARGO_PKG_NAME"),
+    setting = AppSettings::SubcommandsNegateReqs,
+    setting = AppSettings::ArgsNegateSubcommands
+)]
 pub struct App {
-    /// GitHub repo. Required.
-    pub repo: String,
+    /// GitHub or GitLab repo. Required.
+    #[structopt(set = ArgSettings::Required)]
+    pub repo: Option<String>,
 
     /// Destination path.
     #[structopt(default_value = ".")]
     pub destination: String,
 
-    /// Git clone (SSH) instead of tarball via HTTP.
+    /// Git clone (SSH, or HTTPS with a token) instead of tarball via HTTP.
     #[structopt(short, long)]
     pub git: bool,
 
@@ -20,19 +30,120 @@ pub struct App {
     #[structopt(short, long)]
     pub workflows: bool,
 
-    /// Auto-replaces '$default-branch' placeholders.
+    /// Lists the workflow templates in an organization's .github repo.
+    #[structopt(long, conflicts_with_all = &["template", "workflows"])]
+    pub list_templates: bool,
+
+    /// Installs a workflow template from an organization's .github repo into .github/workflows.
+    #[structopt(long, conflicts_with = "workflows")]
+    pub template: Option<String>,
+
+    /// Auto-replaces workflow template placeholders like '$default-branch'.
     #[structopt(short, long)]
     pub default_branch: bool,
 
+    /// Branch for '$protected-branches'.  Repeatable.  Defaults to git config rsieve.protectedBranch.
+    #[structopt(long = "protected-branch", number_of_values = 1)]
+    pub protected_branches: Vec<String>,
+
+    /// Template variable as name=value, for `{{name}}` placeholders.  Repeatable.
+    #[structopt(long = "var", number_of_values = 1, parse(try_from_str = parse_var))]
+    pub vars: Vec<(String, String)>,
+
+    /// Only fills in placeholders in files with this extension.  Repeatable.
+    #[structopt(long = "render-ext", number_of_values = 1)]
+    pub render_exts: Vec<String>,
+
+    /// Fails instead of prompting for template variables without a value.
+    #[structopt(long)]
+    pub no_input: bool,
+
+    /// Adds files to a non-empty destination, keeping any that are already there.
+    #[structopt(short, long)]
+    pub merge: bool,
+
+    /// What to do with files already in the destination: skip, overwrite, backup, rename, or prompt.
+    #[structopt(long, possible_values = &["skip", "overwrite", "backup", "rename", "prompt"])]
+    pub on_conflict: Option<OnConflict>,
+
+    /// Writes a .rsieve.lock to the destination recording the source commit, filters, variables (in plain text) and file hashes.
+    #[structopt(long)]
+    pub lock: bool,
+
     /// Previews without updating destination.
     #[structopt(short, long)]
     pub preview: bool,
 
+    /// Previews what would change in the destination, with diffs of modified files.
+    #[structopt(long, conflicts_with = "preview")]
+    pub diff: bool,
+
     /// Glob filter to get only specific directories and files.
     #[structopt(long)]
     pub filter: Option<String>,
 
+    /// Gitignore-style pattern of paths to get, relative to any subdirectory.  Repeatable.
+    #[structopt(long, number_of_values = 1)]
+    pub include: Vec<String>,
+
+    /// Gitignore-style pattern of paths to leave out.  Repeatable.
+    #[structopt(long, number_of_values = 1)]
+    pub exclude: Vec<String>,
+
+    /// Local file of gitignore-style patterns to leave out.
+    #[structopt(long, parse(from_os_str))]
+    pub ignore_file: Option<PathBuf>,
+
+    /// Keeps matched paths relative to the repo root.  Default for `**` filters.
+    #[structopt(long, conflicts_with = "flatten")]
+    pub keep_paths: bool,
+
+    /// Copies every match by its file name alone.
+    #[structopt(long)]
+    pub flatten: bool,
+
+    /// Keeps matched paths, minus this many leading directories.
+    #[structopt(long, conflicts_with = "flatten")]
+    pub strip_components: Option<usize>,
+
     /// Source branch name.  Defaults to primary branch.
     #[structopt(long)]
     pub branch: Option<String>,
+
+    /// Source tag, commit SHA, or branch.  Also accepted as a `repo#ref` suffix.
+    #[structopt(long = "ref", conflicts_with = "branch")]
+    pub git_ref: Option<String>,
+
+    /// How to handle symlinks in tarballs: skip, preserve, or dereference.  Dereferencing fails on links to
+    /// files missing from the archive.
+    #[structopt(long, default_value = "preserve", possible_values = &["skip", "preserve", "dereference"])]
+    pub symlinks: SymlinkPolicy,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    /// Re-syncs a destination with its upstream, as recorded in its .rsieve.lock.
+    Update(Update),
+}
+
+#[derive(StructOpt, Debug)]
+pub struct Update {
+    /// Destination path holding a .rsieve.lock.
+    #[structopt(default_value = ".")]
+    pub destination: String,
+
+    /// Source branch name to update to, instead of the locked one.
+    #[structopt(long)]
+    pub branch: Option<String>,
+
+    /// Source tag, commit SHA, or branch to update to, instead of the locked one.
+    #[structopt(long = "ref", conflicts_with = "branch")]
+    pub git_ref: Option<String>,
+
+    /// Fails instead of prompting for template variables the lock has no value for.
+    #[structopt(long)]
+    pub no_input: bool,
 }
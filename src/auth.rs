@@ -0,0 +1,145 @@
+// Resolves access tokens for private repos over HTTPS
+use crate::config;
+use crate::repos;
+use config::Config;
+use repos::{Forge, RepoMeta};
+use std::env;
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+/// Hosts the generic token env vars are sent to. Anything else, such as a
+/// GitHub Enterprise server, needs its own `token` or `token_env`.
+const PUBLIC_HOSTS: [&str; 3] = ["github.com", "gitlab.com", "gitea.com"];
+
+/// Finds a token for the repo's host, trying in order the host's configured
+/// token or token env var, the RSIEVE_TOKEN and forge's usual env vars for
+/// public hosts, and finally git's credential helper if the host opts in.
+pub fn token_for(repo: &RepoMeta, config: &Config) -> Option<String> {
+    token_with_env(repo, config, |name| env::var(name).ok())
+}
+
+fn token_with_env(
+    repo: &RepoMeta,
+    config: &Config,
+    env: impl Fn(&str) -> Option<String>,
+) -> Option<String> {
+    let non_empty_env = |name: &str| env(name).filter(|v| !v.is_empty());
+
+    let host = config.host(&repo.host)?;
+    if let Some(t) = host.token.filter(|t| !t.is_empty()) {
+        return Some(t);
+    }
+    if let Some(t) = host.token_env.as_deref().and_then(non_empty_env) {
+        return Some(t);
+    }
+
+    if PUBLIC_HOSTS.contains(&repo.host.as_str()) {
+        let forge_env = match repo.forge {
+            Forge::GitHub => "GITHUB_TOKEN",
+            Forge::GitLab => "GITLAB_TOKEN",
+            Forge::Gitea => "GITEA_TOKEN",
+        };
+        if let Some(t) = non_empty_env("RSIEVE_TOKEN").or_else(|| non_empty_env(forge_env)) {
+            return Some(t);
+        }
+    }
+
+    if host.credential_helper {
+        return credential_fill(&repo.host);
+    }
+
+    None
+}
+
+/// Asks `git credential fill` for the host's password, without prompting.
+fn credential_fill(host: &str) -> Option<String> {
+    let mut child = Command::new("git")
+        .args(["credential", "fill"])
+        .env("GIT_TERMINAL_PROMPT", "0")
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .ok()?;
+
+    child
+        .stdin
+        .take()?
+        .write_all(format!("protocol=https\nhost={}\n\n", host).as_bytes())
+        .ok()?;
+
+    let output = child.wait_with_output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+
+    String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .find_map(|l| l.strip_prefix("password="))
+        .filter(|p| !p.is_empty())
+        .map(|p| p.to_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use repos::parse_repo_input;
+
+    #[test]
+    fn it_uses_configured_tokens() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+            token = "abc123"
+
+            [hosts."code.corp.example"]
+            forge = "gitea"
+            token_env = "RSIEVE_TEST_CODE_CORP_TOKEN"
+            "#,
+        )
+        .unwrap();
+
+        let env = |name: &str| match name {
+            "RSIEVE_TEST_CODE_CORP_TOKEN" => Some("def456".to_owned()),
+            "GITHUB_TOKEN" => Some("ghp_public".to_owned()),
+            _ => None,
+        };
+
+        let ghe = parse_repo_input("ghe.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            token_with_env(&ghe, &config, env).as_deref(),
+            Some("abc123")
+        );
+
+        let gitea = parse_repo_input("code.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            token_with_env(&gitea, &config, env).as_deref(),
+            Some("def456")
+        );
+
+        let github = parse_repo_input("owner/repo", &config).unwrap();
+        assert_eq!(
+            token_with_env(&github, &config, env).as_deref(),
+            Some("ghp_public")
+        );
+    }
+
+    #[test]
+    fn it_keeps_generic_tokens_to_public_hosts() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+            "#,
+        )
+        .unwrap();
+        let env = |name: &str| match name {
+            "RSIEVE_TOKEN" | "GITHUB_TOKEN" => Some("ghp_public".to_owned()),
+            _ => None,
+        };
+
+        let ghe = parse_repo_input("ghe.corp.example/team/repo", &config).unwrap();
+        assert!(token_with_env(&ghe, &config, env).is_none());
+    }
+}
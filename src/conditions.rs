@@ -0,0 +1,143 @@
+// Evaluates the conditions templates use to switch files and blocks on or off
+use crate::placeholders::{is_name, Vars};
+use serde::Deserialize;
+use std::convert::TryFrom;
+use std::fmt;
+use std::str::FromStr;
+
+/// A test on one variable: `name`, `!name`, `name == "value"` or
+/// `name != "value"`.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(try_from = "String")]
+pub enum Condition {
+    Set(String),
+    NotSet(String),
+    Equals(String, String),
+    NotEquals(String, String),
+}
+
+impl Condition {
+    /// The variable tested.
+    pub fn name(&self) -> &str {
+        match self {
+            Condition::Set(name)
+            | Condition::NotSet(name)
+            | Condition::Equals(name, _)
+            | Condition::NotEquals(name, _) => name,
+        }
+    }
+
+    /// Whether the condition holds, or None if its variable has no value.
+    pub fn eval(&self, vars: &Vars) -> Option<bool> {
+        match self {
+            Condition::Set(name) => vars.get(name).map(|v| truthy(v)),
+            Condition::NotSet(name) => vars.get(name).map(|v| !truthy(v)),
+            Condition::Equals(name, value) => vars.get(name).map(|v| v == value),
+            Condition::NotEquals(name, value) => vars.get(name).map(|v| v != value),
+        }
+    }
+}
+
+/// Anything but an empty value, `false`, `no`, `off` or `0` counts as set.
+fn truthy(value: &str) -> bool {
+    !matches!(
+        value.trim().to_ascii_lowercase().as_str(),
+        "" | "false" | "no" | "off" | "0"
+    )
+}
+
+impl FromStr for Condition {
+    type Err = String;
+
+    fn from_str(expr: &str) -> Result<Self, Self::Err> {
+        let bad = || format!("invalid condition '{}'", expr);
+
+        for (op, negated) in [("!=", true), ("==", false)] {
+            if let Some((name, value)) = expr.split_once(op) {
+                let name = name.trim();
+                let value = value.trim();
+                let value = value
+                    .strip_prefix('"')
+                    .and_then(|v| v.strip_suffix('"'))
+                    .unwrap_or(value);
+                if !is_name(name) || value.contains('"') {
+                    return Err(bad());
+                }
+
+                return Ok(if negated {
+                    Condition::NotEquals(name.into(), value.into())
+                } else {
+                    Condition::Equals(name.into(), value.into())
+                });
+            }
+        }
+
+        let expr = expr.trim();
+        match expr.strip_prefix('!') {
+            Some(name) if is_name(name.trim()) => Ok(Condition::NotSet(name.trim().into())),
+            None if is_name(expr) => Ok(Condition::Set(expr.into())),
+            _ => Err(bad()),
+        }
+    }
+}
+
+impl TryFrom<String> for Condition {
+    type Error = String;
+
+    fn try_from(expr: String) -> Result<Self, Self::Error> {
+        expr.parse()
+    }
+}
+
+impl fmt::Display for Condition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Condition::Set(name) => write!(f, "{}", name),
+            Condition::NotSet(name) => write!(f, "!{}", name),
+            Condition::Equals(name, value) => write!(f, "{} == \"{}\"", name, value),
+            Condition::NotEquals(name, value) => write!(f, "{} != \"{}\"", name, value),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_conditions() {
+        assert_eq!(
+            "use_docker".parse(),
+            Ok(Condition::Set("use_docker".into()))
+        );
+        assert_eq!(
+            " ! use_docker".parse(),
+            Ok(Condition::NotSet("use_docker".into()))
+        );
+        assert_eq!(
+            "db == \"postgres\"".parse(),
+            Ok(Condition::Equals("db".into(), "postgres".into()))
+        );
+        assert_eq!(
+            "db!=sqlite".parse(),
+            Ok(Condition::NotEquals("db".into(), "sqlite".into()))
+        );
+        assert!("use docker".parse::<Condition>().is_err());
+        assert!("== x".parse::<Condition>().is_err());
+    }
+
+    #[test]
+    fn it_evaluates_conditions() {
+        let mut vars = Vars::new();
+        vars.insert("use_docker".into(), "false".into());
+        vars.insert("db".into(), "postgres".into());
+
+        assert_eq!(Condition::Set("use_docker".into()).eval(&vars), Some(false));
+        assert_eq!(Condition::NotSet("db".into()).eval(&vars), Some(false));
+        assert_eq!(
+            "db == postgres".parse::<Condition>().unwrap().eval(&vars),
+            Some(true)
+        );
+        assert_eq!(Condition::Set("missing".into()).eval(&vars), None);
+    }
+}
@@ -0,0 +1,124 @@
+// Loads user configuration such as self-hosted forges
+use crate::errors;
+use crate::repos::Forge;
+use errors::{AppResult, Error};
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::{env, fs, path::PathBuf};
+
+#[derive(Deserialize, Debug, Default)]
+pub struct Config {
+    #[serde(default)]
+    pub hosts: HashMap<String, HostConfig>,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct HostConfig {
+    pub forge: Forge,
+    /// Web root of the forge. Defaults to https://<host>.
+    pub base_url: Option<String>,
+    /// Access token for private repos. Prefer `token_env` over storing it here.
+    pub token: Option<String>,
+    /// Name of an env var holding the access token.
+    pub token_env: Option<String>,
+    /// Whether to ask `git credential fill` for a token.
+    #[serde(default)]
+    pub credential_helper: bool,
+}
+
+impl Config {
+    pub fn from_toml(contents: &str) -> AppResult<Config> {
+        toml::from_str(contents).map_err(|e| Error::BadConfig(e.message().into()))
+    }
+
+    /// Looks up a host, including the built-in public forges.
+    pub fn host(&self, host: &str) -> Option<HostConfig> {
+        if let Some(h) = self.hosts.get(host) {
+            return Some(h.clone());
+        }
+
+        let forge = match host {
+            "github.com" => Forge::GitHub,
+            "gitlab.com" => Forge::GitLab,
+            "gitea.com" => Forge::Gitea,
+            _ => return None,
+        };
+
+        Some(HostConfig {
+            forge,
+            base_url: None,
+            token: None,
+            token_env: None,
+            credential_helper: false,
+        })
+    }
+}
+
+/// `RSIEVE_CONFIG`, or the config file in the home directory if there is one.
+pub fn config_path() -> Option<PathBuf> {
+    match env::var_os("RSIEVE_CONFIG") {
+        Some(p) => Some(PathBuf::from(p)),
+        None => home::home_dir().map(|h| h.join(format!(".{}.toml", env!("CARGO_PKG_NAME")))),
+    }
+}
+
+pub fn load() -> AppResult<Config> {
+    let path = match config_path() {
+        Some(p) if p.is_file() => p,
+        _ => return Ok(Config::default()),
+    };
+
+    Config::from_toml(&fs::read_to_string(path)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn it_parses_hosts() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+
+            [hosts."code.corp.example"]
+            forge = "forgejo"
+            base_url = "https://code.corp.example/git"
+            "#,
+        )
+        .expect("config should parse");
+
+        let ghe = config.host("ghe.corp.example").unwrap();
+        assert_eq!(ghe.forge, Forge::GitHub);
+        assert!(ghe.base_url.is_none());
+
+        let forgejo = config.host("code.corp.example").unwrap();
+        assert_eq!(forgejo.forge, Forge::Gitea);
+        assert_eq!(
+            forgejo.base_url.as_deref(),
+            Some("https://code.corp.example/git")
+        );
+    }
+
+    #[test]
+    fn it_knows_public_hosts() {
+        let config = Config::default();
+
+        assert_eq!(config.host("gitlab.com").unwrap().forge, Forge::GitLab);
+        assert_eq!(config.host("gitea.com").unwrap().forge, Forge::Gitea);
+        assert!(config.host("githubs.com").is_none());
+    }
+
+    #[test]
+    fn it_rejects_unknown_forges() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."svn.corp.example"]
+            forge = "svn"
+            "#,
+        );
+
+        assert!(config.is_err());
+    }
+}
@@ -0,0 +1,187 @@
+// Compares what would be copied with what's already in the destination
+use crate::directories::OnConflict;
+use crate::errors;
+use crate::placeholders::decode_text;
+use diffy::DiffOptions;
+use errors::AppResult;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What copying a file would do to the destination.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum Change {
+    New,
+    /// Already there with other contents, which would be replaced.
+    Modified,
+    Unchanged,
+    /// Already there with other contents, which would be kept or asked about.
+    Conflict,
+    /// Already there, and would be moved aside to `<file>.orig` first.
+    BackedUp,
+    /// Already there with other contents, which a copy without `--merge` or
+    /// `--on-conflict` won't touch.
+    Blocked,
+}
+
+#[derive(Debug)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub change: Change,
+    /// Unified diff from the file in the destination, when both are text.
+    pub diff: Option<String>,
+}
+
+/// Compares `source`, a file or directory that would be copied to `dest`,
+/// with what's there now, file by file.
+pub fn compare(
+    source: &Path,
+    dest: &Path,
+    on_conflict: Option<OnConflict>,
+    changes: &mut Vec<FileChange>,
+) -> AppResult<()> {
+    let source_meta = fs::symlink_metadata(source)?;
+    let dest_meta = fs::symlink_metadata(dest).ok();
+
+    if source_meta.is_dir() && dest_meta.as_ref().is_none_or(|m| m.is_dir()) {
+        for entry in fs::read_dir(source)? {
+            let name = entry?.file_name();
+            compare(&source.join(&name), &dest.join(&name), on_conflict, changes)?;
+        }
+        return Ok(());
+    }
+
+    let read = |path: &Path, meta: &fs::Metadata| -> AppResult<Option<Vec<u8>>> {
+        Ok(if meta.is_file() {
+            Some(fs::read(path)?)
+        } else {
+            None
+        })
+    };
+
+    let (change, diff) = match dest_meta {
+        None => (Change::New, None),
+        Some(dest_meta) => {
+            let old = read(dest, &dest_meta)?;
+            let new = read(source, &source_meta)?;
+            if old.is_some() && old == new {
+                (Change::Unchanged, None)
+            } else {
+                // as merge_into would settle it
+                let strategy =
+                    on_conflict.map(|s| s.for_types(source_meta.is_dir(), dest_meta.is_dir()));
+                let change = match strategy {
+                    None => Change::Blocked,
+                    Some(OnConflict::Overwrite) => Change::Modified,
+                    Some(OnConflict::Backup) => Change::BackedUp,
+                    Some(OnConflict::Skip | OnConflict::Rename | OnConflict::Prompt) => {
+                        Change::Conflict
+                    }
+                };
+                let old = old.as_deref().and_then(decode_text);
+                let new = new.as_deref().and_then(decode_text);
+                let diff = match (old, new) {
+                    (Some(old), Some(new)) => Some(unified_diff(dest, &old, &new)),
+                    _ => None,
+                };
+                (change, diff)
+            }
+        }
+    };
+
+    changes.push(FileChange {
+        path: dest.to_path_buf(),
+        change,
+        diff,
+    });
+
+    Ok(())
+}
+
+fn unified_diff(path: &Path, old: &str, new: &str) -> String {
+    DiffOptions::new()
+        .set_original_filename(format!("a/{}", path.display()))
+        .set_modified_filename(format!("b/{}", path.display()))
+        .create_patch(old, new)
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    #[test]
+    fn it_labels_and_diffs_changes() {
+        let base = test_dir("it_labels_and_diffs_changes");
+        let (source, dest) = (base.join("source"), base.join("dest"));
+        for dir in [&source, &dest] {
+            fs::create_dir_all(dir).unwrap();
+            fs::write(dir.join("same.yml"), "on: push\n").unwrap();
+            fs::write(dir.join("logo.png"), [0u8, 1, 2]).unwrap();
+        }
+        fs::write(source.join("ci.yml"), "on: push\nruns-on: ubuntu\n").unwrap();
+        fs::write(dest.join("ci.yml"), "on: push\nruns-on: macos\n").unwrap();
+        fs::write(source.join("new.yml"), "on: pull_request\n").unwrap();
+        fs::write(source.join("logo.png"), [0u8, 1, 3]).unwrap();
+
+        let mut changes = Vec::new();
+        compare(&source, &dest, Some(OnConflict::Overwrite), &mut changes).unwrap();
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        let labels: Vec<(&Path, Change)> = changes
+            .iter()
+            .map(|c| (c.path.strip_prefix(&dest).unwrap(), c.change))
+            .collect();
+        assert_eq!(
+            labels,
+            vec![
+                (Path::new("ci.yml"), Change::Modified),
+                (Path::new("logo.png"), Change::Modified),
+                (Path::new("new.yml"), Change::New),
+                (Path::new("same.yml"), Change::Unchanged),
+            ]
+        );
+
+        let diff = changes[0].diff.as_deref().unwrap();
+        assert!(diff.contains("-runs-on: macos\n+runs-on: ubuntu\n"));
+        assert!(changes[1].diff.is_none());
+
+        let mut changes = Vec::new();
+        compare(
+            &source.join("ci.yml"),
+            &dest.join("ci.yml"),
+            Some(OnConflict::Skip),
+            &mut changes,
+        )
+        .unwrap();
+        assert_eq!(changes[0].change, Change::Conflict);
+
+        // a real copy without a strategy refuses an occupied destination
+        let mut changes = Vec::new();
+        compare(
+            &source.join("ci.yml"),
+            &dest.join("ci.yml"),
+            None,
+            &mut changes,
+        )
+        .unwrap();
+        assert_eq!(changes[0].change, Change::Blocked);
+
+        // a directory is never overwritten by a file, but backed up
+        fs::create_dir_all(source.join("docs")).unwrap();
+        fs::write(source.join("docs/index.md"), "docs\n").unwrap();
+        fs::write(dest.join("docs"), "notes\n").unwrap();
+        let mut changes = Vec::new();
+        compare(
+            &source.join("docs"),
+            &dest.join("docs"),
+            Some(OnConflict::Overwrite),
+            &mut changes,
+        )
+        .unwrap();
+        assert_eq!(changes[0].change, Change::BackedUp);
+        assert_eq!(changes[0].path, dest.join("docs"));
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
@@ -1,11 +1,17 @@
+use crate::diffs;
+use crate::downloaders;
 use crate::errors;
-use crate::git;
 use crate::placeholders;
+use crate::prompts;
 use crate::tree;
 use errors::{AppResult, Error};
 use glob::glob;
-use placeholders::update_placeholder_branch;
-use std::{fs, path::PathBuf};
+use placeholders::{Placeholders, Unresolved};
+use std::collections::HashMap;
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
 use tree::directory_tree;
 
 pub fn prep_tmp_dir() -> AppResult<String> {
@@ -24,6 +30,49 @@ pub fn prep_tmp_dir() -> AppResult<String> {
     Ok(home_dir)
 }
 
+/// A path in the system temp dir for a test to work in, unique to this run.
+#[cfg(test)]
+pub fn test_dir(test_name: &str) -> PathBuf {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    let ms = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .expect("Time went backwards")
+        .as_millis();
+    std::env::temp_dir().join(format!("{}-{}", test_name, ms))
+}
+
+/// Makes `path` absolute, resolving the part that exists, so a destination
+/// can be described before it's created.
+pub fn resolve(path: &str) -> PathBuf {
+    let path = Path::new(path);
+    for ancestor in path.ancestors() {
+        let existing = if ancestor.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            ancestor
+        };
+        if let Ok(base) = fs::canonicalize(existing) {
+            return base.join(path.strip_prefix(ancestor).unwrap_or(path));
+        }
+    }
+
+    path.to_path_buf()
+}
+
+pub fn source_dir(tmp_dir: &str, subdir: &Option<String>) -> AppResult<String> {
+    match subdir {
+        Some(sub) => {
+            let dir = format!("{}/{}", tmp_dir, sub);
+            if !std::path::Path::new(&dir).is_dir() {
+                return Err(Error::BadSubdirectory(sub.into()));
+            }
+            Ok(dir)
+        }
+        None => Ok(tmp_dir.into()),
+    }
+}
+
 pub fn check_distination(destination: &str, force: bool) -> AppResult<String> {
     let path = PathBuf::from(destination);
     if path.exists() {
@@ -39,66 +88,424 @@ pub fn check_distination(destination: &str, force: bool) -> AppResult<String> {
     Ok(destination.into())
 }
 
+/// Where matches land in the destination.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Layout {
+    /// Each match by its file name alone.
+    Flatten,
+    /// Each match at its path in the repo, minus `strip` leading components.
+    Keep { strip: usize },
+}
+
+/// What a preview shows instead of copying.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Preview {
+    /// The matched files, as trees.
+    Tree,
+    /// What would change in the destination, with diffs of modified files.
+    Diff,
+}
+
+/// Keeps paths when asked to, or for deep (`**`) filters where flattening
+/// would lose where files came from.
+pub fn choose_layout(
+    filter: Option<&str>,
+    flatten: bool,
+    keep_paths: bool,
+    strip_components: Option<usize>,
+) -> Layout {
+    match (flatten, keep_paths, strip_components) {
+        (true, _, _) => Layout::Flatten,
+        (_, _, Some(strip)) => Layout::Keep { strip },
+        (_, true, _) => Layout::Keep { strip: 0 },
+        _ if filter.unwrap_or_default().contains("**") => Layout::Keep { strip: 0 },
+        _ => Layout::Flatten,
+    }
+}
+
 pub fn move_to_destination(
     tmp_dir: &str,
     destination: &str,
     filter: Option<String>,
-    preview: bool,
-    update_default_branch: bool,
-) -> AppResult<()> {
+    preview: Option<Preview>,
+    placeholders: Option<&Placeholders>,
+    layout: Layout,
+    on_conflict: Option<OnConflict>,
+) -> AppResult<Report> {
     let full_filter = match filter {
         Some(f) => format!("{}/{}", &tmp_dir, &f),
         None => format!("{}/{}", &tmp_dir, "*"),
     };
 
-    let matches = glob(&full_filter).expect("Failed to read glob pattern");
-    if matches.count() == usize::MIN {
+    let mut matches: Vec<PathBuf> = Vec::new();
+    for entry in glob(&full_filter).expect("Failed to read glob pattern") {
+        match entry {
+            Ok(path) => matches.push(path),
+            Err(e) => println!("{:?}", e),
+        }
+    }
+    if matches.is_empty() {
+        return Err(Error::NoMatchingFiles);
+    }
+
+    // a directory match already carries everything below it
+    matches.sort();
+    matches.dedup_by(|later, earlier| later.starts_with(earlier));
+
+    let mut unresolved = Unresolved::new();
+    let moves = plan_moves(
+        tmp_dir,
+        destination,
+        &matches,
+        layout,
+        placeholders,
+        &mut unresolved,
+    )?;
+    if moves.is_empty() {
         return Err(Error::NoMatchingFiles);
     }
 
+    if preview == Some(Preview::Tree) {
+        for path in matches {
+            directory_tree(path)?;
+        }
+        return Ok(Report::default());
+    }
+
+    // render everything first, so a clash between rendered names stops the
+    // copy before anything is written
+    if let Some(p) = placeholders {
+        for (source_path, dest_path) in &moves {
+            p.render_path(source_path, dest_path, &mut unresolved)?;
+        }
+    }
+
+    if preview == Some(Preview::Diff) {
+        let mut changes = Vec::new();
+        for (source_path, dest_path) in &moves {
+            diffs::compare(source_path, dest_path, on_conflict, &mut changes)?;
+        }
+        changes.sort_by(|a, b| a.path.cmp(&b.path));
+
+        return Ok(Report {
+            unresolved,
+            changes,
+            ..Report::default()
+        });
+    }
+
     let destination_path = PathBuf::from(&destination);
 
-    if !preview && !destination_path.exists() {
-        fs::create_dir(&destination_path)?;
+    if !destination_path.exists() {
+        // templates go to .github/workflows, which a repo may not have yet
+        fs::create_dir_all(&destination_path)?;
     }
 
-    let default_branch = if update_default_branch {
-        let res = git::default_branch(destination);
-        match res {
-            Ok(r) => r,
-            Err(_) => "main".into(),
+    let mut files = Vec::new();
+    let mut on_conflict = on_conflict;
+    for (source_path, dest_path) in moves {
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        match &mut on_conflict {
+            Some(strategy) => merge_into(&source_path, &dest_path, strategy, &mut files)?,
+            None => {
+                fs::rename(&source_path, &dest_path)?;
+                list_added(&dest_path, &mut files)?;
+            }
+        }
+    }
+    files.sort();
+
+    Ok(Report {
+        unresolved,
+        files,
+        ..Report::default()
+    })
+}
+
+/// What to do with a file that's already in the destination.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum OnConflict {
+    /// Keeps the existing file.
+    Skip,
+    /// Replaces the existing file. A directory in the way of a file, or a
+    /// file in the way of a directory, is backed up instead.
+    Overwrite,
+    /// Moves the existing file aside to `<file>.orig` first.
+    Backup,
+    /// Writes the new file next to it as `<file>.new`.
+    Rename,
+    /// Asks for each file.
+    Prompt,
+}
+
+impl std::str::FromStr for OnConflict {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(OnConflict::Skip),
+            "overwrite" => Ok(OnConflict::Overwrite),
+            "backup" => Ok(OnConflict::Backup),
+            "rename" => Ok(OnConflict::Rename),
+            "prompt" => Ok(OnConflict::Prompt),
+            _ => Err(format!("unknown conflict strategy {}", s)),
+        }
+    }
+}
+
+impl OnConflict {
+    /// The strategy that's really used for a source landing on something
+    /// already in the destination. A file and a directory never overwrite
+    /// each other, so they're backed up instead.
+    pub fn for_types(self, source_is_dir: bool, dest_is_dir: bool) -> OnConflict {
+        match self {
+            OnConflict::Overwrite if source_is_dir != dest_is_dir => OnConflict::Backup,
+            strategy => strategy,
+        }
+    }
+}
+
+/// What happened to a file merged into an existing destination.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum Outcome {
+    Added,
+    /// Already there with the same contents.
+    Unchanged,
+    /// Already there with other contents, which were kept.
+    Skipped,
+    Overwritten,
+    /// Replaced, with the old file kept at this path.
+    BackedUp(PathBuf),
+    /// Left alone, with the new file written to this path.
+    Renamed(PathBuf),
+}
+
+#[derive(Default)]
+pub struct Report {
+    pub unresolved: Unresolved,
+    /// Each file written, or skipped when merging.
+    pub files: Vec<(PathBuf, Outcome)>,
+    /// What would change in the destination, when previewing a diff.
+    pub changes: Vec<diffs::FileChange>,
+}
+
+/// Moves `source` to `dest` file by file, settling files already at `dest`
+/// with the strategy. A prompt answered "for all" replaces the strategy.
+fn merge_into(
+    source: &Path,
+    dest: &Path,
+    on_conflict: &mut OnConflict,
+    files: &mut Vec<(PathBuf, Outcome)>,
+) -> AppResult<()> {
+    let source_meta = fs::symlink_metadata(source)?;
+    let dest_meta = fs::symlink_metadata(dest).ok();
+
+    if source_meta.is_dir() {
+        match &dest_meta {
+            None => fs::create_dir(dest)?,
+            Some(m) if m.is_dir() => {}
+            // a file in the way is settled like any other conflict
+            Some(_) => return settle(source, dest, on_conflict, files),
+        }
+        for entry in fs::read_dir(source)? {
+            let name = entry?.file_name();
+            merge_into(&source.join(&name), &dest.join(&name), on_conflict, files)?;
+        }
+        return Ok(());
+    }
+
+    let dest_meta = match dest_meta {
+        Some(m) => m,
+        None => {
+            fs::rename(source, dest)?;
+            files.push((dest.to_path_buf(), Outcome::Added));
+            return Ok(());
         }
-    } else {
-        "".into()
     };
+    if dest_meta.is_file() && source_meta.is_file() && fs::read(source)? == fs::read(dest)? {
+        files.push((dest.to_path_buf(), Outcome::Unchanged));
+        return Ok(());
+    }
 
-    // let default_branch_val = default_branch.unwrap_or_default();
+    settle(source, dest, on_conflict, files)
+}
 
-    for entry in glob(&full_filter).expect("Failed to read glob pattern") {
-        if preview {
-            directory_tree(entry.expect("Error traversing directory."))?;
-        } else {
-            match entry {
-                Ok(path) => {
-                    let source_file = path.display().to_string();
-                    let file_name = path
-                        .file_name()
-                        .expect("File should have a name")
-                        .to_owned()
-                        .to_str()
-                        .unwrap_or_default()
-                        .to_owned();
-                    let dest_file = format!("{}/{}", &destination, &file_name);
-
-                    // println!("Copy from {} to {}", &source_file, &dest_file);
-                    if update_default_branch {
-                        update_placeholder_branch(&source_file, &default_branch)?;
-                    }
-                    fs::rename(&source_file, &dest_file)?;
-                }
-                Err(e) => println!("{:?}", e),
+/// Settles `source` landing on something else already at `dest`.
+fn settle(
+    source: &Path,
+    dest: &Path,
+    on_conflict: &mut OnConflict,
+    files: &mut Vec<(PathBuf, Outcome)>,
+) -> AppResult<()> {
+    let strategy = match *on_conflict {
+        OnConflict::Prompt => {
+            let (choice, for_all) =
+                prompts::ask_conflict(dest, &mut io::stdin().lock(), &mut io::stderr())?;
+            if for_all {
+                *on_conflict = choice;
             }
+            choice
         }
+        strategy => strategy,
+    };
+    let strategy = strategy.for_types(
+        fs::symlink_metadata(source)?.is_dir(),
+        fs::symlink_metadata(dest)?.is_dir(),
+    );
+
+    let outcome = match strategy {
+        OnConflict::Skip | OnConflict::Prompt => Outcome::Skipped,
+        OnConflict::Overwrite => {
+            fs::remove_file(dest)?;
+            fs::rename(source, dest)?;
+            Outcome::Overwritten
+        }
+        OnConflict::Backup => {
+            let backup = free_path(dest, "orig");
+            fs::rename(dest, &backup)?;
+            fs::rename(source, dest)?;
+            Outcome::BackedUp(backup)
+        }
+        OnConflict::Rename => {
+            let renamed = free_path(dest, "new");
+            fs::rename(source, &renamed)?;
+            Outcome::Renamed(renamed)
+        }
+    };
+    files.push((dest.to_path_buf(), outcome));
+
+    Ok(())
+}
+
+/// Notes every file under a path that was moved in whole.
+fn list_added(path: &Path, files: &mut Vec<(PathBuf, Outcome)>) -> AppResult<()> {
+    if fs::symlink_metadata(path)?.is_dir() {
+        for entry in fs::read_dir(path)? {
+            list_added(&entry?.path(), files)?;
+        }
+    } else {
+        files.push((path.to_path_buf(), Outcome::Added));
+    }
+
+    Ok(())
+}
+
+/// `<path>.<suffix>`, or `<path>.<suffix>.2` and so on if that's taken.
+pub fn free_path(path: &Path, suffix: &str) -> PathBuf {
+    let mut n = 1;
+    loop {
+        let mut name = path.as_os_str().to_owned();
+        name.push(format!(".{}", suffix));
+        if n > 1 {
+            name.push(format!(".{}", n));
+        }
+
+        let candidate = PathBuf::from(name);
+        if fs::symlink_metadata(&candidate).is_err() {
+            return candidate;
+        }
+        n += 1;
+    }
+}
+
+/// Pairs each match with where it goes, with any placeholders in the path
+/// rendered, failing if two would land on the same path.
+fn plan_moves(
+    tmp_dir: &str,
+    destination: &str,
+    matches: &[PathBuf],
+    layout: Layout,
+    placeholders: Option<&Placeholders>,
+    unresolved: &mut Unresolved,
+) -> AppResult<Vec<(PathBuf, PathBuf)>> {
+    let mut moves = Vec::new();
+    let mut sources: HashMap<PathBuf, &Path> = HashMap::new();
+
+    let mut expanded = Vec::new();
+    for path in matches {
+        match layout {
+            Layout::Keep { strip } => expand_stripped(tmp_dir, path, strip, &mut expanded)?,
+            Layout::Flatten => expanded.push(path.clone()),
+        }
+    }
+
+    for path in &expanded {
+        let rel_path = path.strip_prefix(tmp_dir)?;
+        let dest_rel: PathBuf = match layout {
+            Layout::Flatten => PathBuf::from(path.file_name().expect("File should have a name")),
+            Layout::Keep { strip } => rel_path.components().skip(strip).collect(),
+        };
+
+        let dest_rel = match placeholders {
+            Some(p) => {
+                let (rendered, names) = p.render_name(&dest_rel)?;
+                placeholders::record(unresolved, names, &Path::new(destination).join(&rendered));
+                rendered
+            }
+            None => dest_rel,
+        };
+
+        if let Some(earlier) = sources.insert(dest_rel.clone(), rel_path) {
+            return Err(Error::PathCollision(
+                dest_rel.display().to_string(),
+                earlier.display().to_string(),
+                rel_path.display().to_string(),
+            ));
+        }
+        // links were only checked against the whole repo, but a subdirectory
+        // or a flattened path moves them closer to the destination's root
+        check_links(path, &dest_rel)?;
+        moves.push((path.clone(), Path::new(destination).join(dest_rel)));
+    }
+
+    Ok(moves)
+}
+
+/// Replaces a directory no deeper than the stripped prefix with what's in
+/// it, and drops a file that shallow, so like tar only the prefix is lost.
+fn expand_stripped(
+    tmp_dir: &str,
+    path: &Path,
+    strip: usize,
+    expanded: &mut Vec<PathBuf>,
+) -> AppResult<()> {
+    if path.strip_prefix(tmp_dir)?.components().count() > strip {
+        expanded.push(path.to_path_buf());
+        return Ok(());
+    }
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(());
+    }
+
+    let mut children = fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<Vec<_>, _>>()?;
+    children.sort();
+    for child in children {
+        expand_stripped(tmp_dir, &child, strip, expanded)?;
+    }
+
+    Ok(())
+}
+
+/// Fails if a symlink at or below `source`, once moved to `dest_rel` in
+/// the destination, would point outside the destination.
+fn check_links(source: &Path, dest_rel: &Path) -> AppResult<()> {
+    let meta = fs::symlink_metadata(source)?;
+
+    if meta.is_dir() {
+        for entry in fs::read_dir(source)? {
+            let name = entry?.file_name();
+            check_links(&source.join(&name), &dest_rel.join(&name))?;
+        }
+    } else if meta.file_type().is_symlink()
+        && !downloaders::link_stays_within(dest_rel, &fs::read_link(source)?)
+    {
+        return Err(Error::LinkOutsideDestination(
+            dest_rel.display().to_string(),
+        ));
     }
 
     Ok(())
@@ -122,6 +529,15 @@ mod tests {
         assert_eq!(path.exists(), true);
     }
 
+    #[test]
+    fn it_finds_source_subdirectories() {
+        let res = source_dir("tests", &Some("sample_templates".into()));
+        assert_eq!(res.unwrap_or_default(), "tests/sample_templates");
+
+        let res = source_dir("tests", &Some("asdfghjkl".into()));
+        assert!(res.is_err());
+    }
+
     #[test]
     fn it_checks_empty_distination() {
         let destination = "asdfghjkl";
@@ -149,9 +565,9 @@ mod tests {
         let src = "tests/test_dir";
         let dest = "it_moves_to_distination";
         let filter = None;
-        let preview = false;
+        let preview = None;
 
-        let res = move_to_destination(src, dest, filter, preview, false);
+        let res = move_to_destination(src, dest, filter, preview, None, Layout::Flatten, None);
         assert_eq! {res.is_ok() , true};
     }
 
@@ -160,9 +576,9 @@ mod tests {
         let src = "tests/test_dirs";
         let dest = "it_doesnt_move_nonexist_to_distination";
         let filter = None;
-        let preview = false;
+        let preview = None;
 
-        let res = move_to_destination(src, dest, filter, preview, false);
+        let res = move_to_destination(src, dest, filter, preview, None, Layout::Flatten, None);
         assert_eq! {res.is_err() , true};
     }
 
@@ -173,9 +589,9 @@ mod tests {
         let src = "tests/test_dir3";
         let dest = "it_previews_move_to_distination";
         let filter = None;
-        let preview = true;
+        let preview = Some(Preview::Tree);
 
-        let res = move_to_destination(src, dest, filter, preview, false);
+        let res = move_to_destination(src, dest, filter, preview, None, Layout::Flatten, None);
         assert_eq! {res.is_ok() , true};
 
         let path = PathBuf::from(dest);
@@ -189,9 +605,9 @@ mod tests {
         let src = "tests/test_dir2";
         let dest = "it_filters_move_to_distination";
         let filter = Some("*.md".into());
-        let preview = false;
+        let preview = None;
 
-        let res = move_to_destination(src, dest, filter, preview, false);
+        let res = move_to_destination(src, dest, filter, preview, None, Layout::Flatten, None);
         assert_eq! {res.is_ok() , true};
 
         let path = PathBuf::from(dest);
@@ -205,4 +621,419 @@ mod tests {
             assert_eq!(contains_a_file, true);
         }
     }
+
+    fn nested_source(test_name: &str) -> PathBuf {
+        let base = test_dir(test_name);
+        for dir in ["src/a", "src/b"] {
+            fs::create_dir_all(base.join("source").join(dir)).unwrap();
+            fs::write(base.join("source").join(dir).join("mod.rs"), dir).unwrap();
+        }
+
+        base
+    }
+
+    #[test]
+    fn it_chooses_layouts() {
+        assert_eq!(
+            choose_layout(Some("*.md"), false, false, None),
+            Layout::Flatten
+        );
+        assert_eq!(
+            choose_layout(Some("src/**/*.rs"), false, false, None),
+            Layout::Keep { strip: 0 }
+        );
+        assert_eq!(
+            choose_layout(Some("src/**/*.rs"), true, false, None),
+            Layout::Flatten
+        );
+        assert_eq!(
+            choose_layout(None, false, false, Some(1)),
+            Layout::Keep { strip: 1 }
+        );
+    }
+
+    #[test]
+    fn it_keeps_paths_for_deep_filters() {
+        let base = nested_source("it_keeps_paths_for_deep_filters");
+        let src = base.join("source").display().to_string();
+        let dest = base.join("dest").display().to_string();
+
+        let res = move_to_destination(
+            &src,
+            &dest,
+            Some("src/**/*.rs".into()),
+            None,
+            None,
+            Layout::Keep { strip: 1 },
+            None,
+        );
+        assert!(res.is_ok());
+        assert!(base.join("dest/a/mod.rs").exists());
+        assert!(base.join("dest/b/mod.rs").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_strips_directory_matches() {
+        let base = nested_source("it_strips_directory_matches");
+        fs::write(base.join("source/README.md"), "readme").unwrap();
+        let src = base.join("source").display().to_string();
+
+        for (filter, dest) in [(Some("src".to_string()), "filtered"), (None, "whole")] {
+            let dest = base.join(dest);
+            let res = move_to_destination(
+                &src,
+                &dest.display().to_string(),
+                filter,
+                Some(Preview::Diff),
+                None,
+                Layout::Keep { strip: 1 },
+                None,
+            );
+            let changes: Vec<PathBuf> = res.unwrap().changes.into_iter().map(|c| c.path).collect();
+            assert_eq!(changes, vec![dest.join("a/mod.rs"), dest.join("b/mod.rs")]);
+        }
+
+        let res = move_to_destination(
+            &src,
+            &base.join("dest").display().to_string(),
+            Some("README.md".into()),
+            None,
+            None,
+            Layout::Keep { strip: 1 },
+            None,
+        );
+        assert!(matches!(res, Err(Error::NoMatchingFiles)));
+        assert!(!base.join("dest").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_reports_flattened_collisions() {
+        let base = nested_source("it_reports_flattened_collisions");
+        let src = base.join("source").display().to_string();
+        let dest = base.join("dest").display().to_string();
+
+        let res = move_to_destination(
+            &src,
+            &dest,
+            Some("src/**/*.rs".into()),
+            None,
+            None,
+            Layout::Flatten,
+            None,
+        );
+        assert!(matches!(res, Err(Error::PathCollision(..))));
+        assert!(!base.join("dest").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_renders_placeholders_while_moving() {
+        let base = nested_source("it_renders_placeholders_while_moving");
+        fs::write(base.join("source/src/a/mod.rs"), "{{name}} {{team}}").unwrap();
+        let src = base.join("source").display().to_string();
+        let dest = base.join("dest").display().to_string();
+
+        let mut vars = placeholders::Vars::new();
+        vars.insert("name".into(), "billing".into());
+        let res = move_to_destination(
+            &src,
+            &dest,
+            None,
+            None,
+            Some(&Placeholders::new(vars)),
+            Layout::Keep { strip: 0 },
+            None,
+        );
+
+        let unresolved = res.expect("move should succeed").unresolved;
+        let contents = fs::read_to_string(base.join("dest/src/a/mod.rs")).unwrap();
+        assert_eq!(contents, "billing {{team}}");
+        assert_eq!(
+            unresolved["team"].iter().collect::<Vec<_>>(),
+            vec![&base.join("dest/src/a/mod.rs")]
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_diffs_rendered_files_without_writing() {
+        let base = nested_source("it_diffs_rendered_files_without_writing");
+        fs::write(base.join("source/src/a/mod.rs"), "{{name}}\n").unwrap();
+        let dest = base.join("dest");
+        fs::create_dir_all(dest.join("src/a")).unwrap();
+        fs::write(dest.join("src/a/mod.rs"), "payments\n").unwrap();
+
+        let mut vars = placeholders::Vars::new();
+        vars.insert("name".into(), "billing".into());
+        let report = move_to_destination(
+            &base.join("source").display().to_string(),
+            &dest.display().to_string(),
+            None,
+            Some(Preview::Diff),
+            Some(&Placeholders::new(vars)),
+            Layout::Keep { strip: 0 },
+            Some(OnConflict::Overwrite),
+        )
+        .expect("preview should succeed");
+
+        let changes: Vec<(PathBuf, diffs::Change)> = report
+            .changes
+            .iter()
+            .map(|c| (c.path.clone(), c.change))
+            .collect();
+        assert_eq!(
+            changes,
+            vec![
+                (dest.join("src/a/mod.rs"), diffs::Change::Modified),
+                (dest.join("src/b/mod.rs"), diffs::Change::New),
+            ]
+        );
+        let diff = report.changes[0].diff.as_deref().unwrap();
+        assert!(diff.contains("-payments\n+billing\n"));
+        assert_eq!(
+            fs::read_to_string(dest.join("src/a/mod.rs")).unwrap(),
+            "payments\n"
+        );
+        assert!(!dest.join("src/b").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_renders_placeholders_in_names() {
+        let base = nested_source("it_renders_placeholders_in_names");
+        let workflows = base.join("source/{{name}}/workflows");
+        fs::create_dir_all(&workflows).unwrap();
+        fs::write(workflows.join("$name-deploy.yml"), "on: push").unwrap();
+        let src = base.join("source").display().to_string();
+        let dest = base.join("dest").display().to_string();
+
+        let mut vars = placeholders::Vars::new();
+        vars.insert("name".into(), "billing".into());
+        let res = move_to_destination(
+            &src,
+            &dest,
+            None,
+            None,
+            Some(&Placeholders::new(vars)),
+            Layout::Keep { strip: 0 },
+            None,
+        );
+
+        assert!(res.is_ok());
+        assert!(base
+            .join("dest/billing/workflows/billing-deploy.yml")
+            .exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_detects_rendered_name_collisions() {
+        let base = nested_source("it_detects_rendered_name_collisions");
+        fs::write(base.join("source/src/{{name}}.rs"), "").unwrap();
+        fs::write(base.join("source/src/billing.rs"), "").unwrap();
+        let src = base.join("source").display().to_string();
+        let dest = base.join("dest").display().to_string();
+
+        let mut vars = placeholders::Vars::new();
+        vars.insert("name".into(), "billing".into());
+        let res = move_to_destination(
+            &src,
+            &dest,
+            None,
+            None,
+            Some(&Placeholders::new(vars)),
+            Layout::Keep { strip: 0 },
+            None,
+        );
+
+        assert!(matches!(res, Err(Error::PathCollision(..))));
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_merges_without_clobbering() {
+        let base = nested_source("it_merges_without_clobbering");
+        let dest = base.join("dest");
+        fs::create_dir_all(dest.join("src/a")).unwrap();
+        fs::write(dest.join("src/a/mod.rs"), "src/a").unwrap();
+        fs::create_dir_all(dest.join("src/b")).unwrap();
+        fs::write(dest.join("src/b/mod.rs"), "mine").unwrap();
+        fs::write(dest.join("README.md"), "unrelated").unwrap();
+        fs::write(base.join("source/src/new.rs"), "new").unwrap();
+
+        let report = move_to_destination(
+            &base.join("source").display().to_string(),
+            &dest.display().to_string(),
+            None,
+            None,
+            None,
+            Layout::Keep { strip: 0 },
+            Some(OnConflict::Skip),
+        )
+        .expect("merge should succeed");
+
+        assert_eq!(
+            report.files,
+            vec![
+                (dest.join("src/a/mod.rs"), Outcome::Unchanged),
+                (dest.join("src/b/mod.rs"), Outcome::Skipped),
+                (dest.join("src/new.rs"), Outcome::Added),
+            ]
+        );
+        assert_eq!(
+            fs::read_to_string(dest.join("src/b/mod.rs")).unwrap(),
+            "mine"
+        );
+        assert_eq!(fs::read_to_string(dest.join("src/new.rs")).unwrap(), "new");
+        assert!(dest.join("README.md").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    /// A source tree with `src/a/mod.rs`, and a destination where that
+    /// file has other contents and a backup from earlier.
+    fn conflicting(test_name: &str) -> (PathBuf, PathBuf) {
+        let base = nested_source(test_name);
+        let dest = base.join("dest");
+        fs::create_dir_all(dest.join("src/a")).unwrap();
+        fs::write(dest.join("src/a/mod.rs"), "mine").unwrap();
+        fs::write(dest.join("src/a/mod.rs.orig"), "older").unwrap();
+
+        (base, dest)
+    }
+
+    fn merge(base: &Path, dest: &Path, strategy: OnConflict) -> Report {
+        move_to_destination(
+            &base.join("source").display().to_string(),
+            &dest.display().to_string(),
+            Some("src/a".into()),
+            None,
+            None,
+            Layout::Keep { strip: 0 },
+            Some(strategy),
+        )
+        .expect("merge should succeed")
+    }
+
+    #[test]
+    fn it_backs_up_conflicts() {
+        let (base, dest) = conflicting("it_backs_up_conflicts");
+
+        let report = merge(&base, &dest, OnConflict::Backup);
+
+        let file = dest.join("src/a/mod.rs");
+        let backup = dest.join("src/a/mod.rs.orig.2");
+        assert_eq!(
+            report.files,
+            vec![(file.clone(), Outcome::BackedUp(backup.clone()))]
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "src/a");
+        assert_eq!(fs::read_to_string(&backup).unwrap(), "mine");
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_renames_conflicts() {
+        let (base, dest) = conflicting("it_renames_conflicts");
+
+        let report = merge(&base, &dest, OnConflict::Rename);
+
+        let file = dest.join("src/a/mod.rs");
+        let renamed = dest.join("src/a/mod.rs.new");
+        assert_eq!(
+            report.files,
+            vec![(file.clone(), Outcome::Renamed(renamed.clone()))]
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "mine");
+        assert_eq!(fs::read_to_string(&renamed).unwrap(), "src/a");
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_backs_up_directories_instead_of_overwriting() {
+        let base = nested_source("it_backs_up_directories_instead_of_overwriting");
+        let dest = base.join("dest");
+        fs::create_dir_all(dest.join("src/a/mod.rs")).unwrap();
+        fs::write(dest.join("src/a/mod.rs/notes.md"), "mine").unwrap();
+
+        let report = merge(&base, &dest, OnConflict::Overwrite);
+
+        let file = dest.join("src/a/mod.rs");
+        let backup = dest.join("src/a/mod.rs.orig");
+        assert_eq!(
+            report.files,
+            vec![(file.clone(), Outcome::BackedUp(backup.clone()))]
+        );
+        assert_eq!(fs::read_to_string(&file).unwrap(), "src/a");
+        assert_eq!(fs::read_to_string(backup.join("notes.md")).unwrap(), "mine");
+
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn it_rejects_links_leaving_a_subdirectory_source() {
+        use std::os::unix::fs::symlink;
+
+        let base = test_dir("it_rejects_links_leaving_a_subdirectory_source");
+        let repo = base.join("repo");
+        fs::create_dir_all(repo.join("sub/docs")).unwrap();
+        fs::write(repo.join("README.md"), "readme").unwrap();
+        fs::write(repo.join("sub/guide.md"), "guide").unwrap();
+        symlink("../guide.md", repo.join("sub/docs/guide.md")).unwrap();
+        let source = source_dir(&repo.display().to_string(), &Some("sub".into())).unwrap();
+        let dest = base.join("dest").display().to_string();
+
+        // within the subdirectory, the link still lands next to its target
+        let res = move_to_destination(
+            &source,
+            &dest,
+            None,
+            Some(Preview::Diff),
+            None,
+            Layout::Keep { strip: 0 },
+            None,
+        );
+        assert!(res.is_ok());
+
+        // fine in the repo, but one level up from the destination once moved
+        symlink("../README.md", repo.join("sub/readme.md")).unwrap();
+        let res = move_to_destination(
+            &source,
+            &dest,
+            None,
+            None,
+            None,
+            Layout::Keep { strip: 0 },
+            None,
+        );
+        assert!(matches!(res, Err(Error::LinkOutsideDestination(p)) if p == "readme.md"));
+        assert!(!base.join("dest").exists());
+
+        // flattening moves a nested link up to the root, out of its target's reach
+        fs::remove_file(repo.join("sub/readme.md")).unwrap();
+        let res = move_to_destination(
+            &source,
+            &dest,
+            Some("docs/*.md".into()),
+            None,
+            None,
+            Layout::Flatten,
+            None,
+        );
+        assert!(matches!(res, Err(Error::LinkOutsideDestination(p)) if p == "guide.md"));
+
+        fs::remove_dir_all(base).unwrap();
+    }
 }
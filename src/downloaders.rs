@@ -1,101 +1,485 @@
 use crate::errors;
+use crate::filters;
+use crate::git;
+use crate::repos;
+use bytes::Bytes;
+use console::style;
 use errors::{AppResult, Error};
+use filters::PathFilter;
 use flate2::read::GzDecoder;
-use run_script::ScriptOptions;
+use repos::{Forge, GitRef, RepoMeta};
+use serde::{Deserialize, Serialize};
 use std::fs;
+use std::io::{self, Read};
+use std::path::{Component, Path, PathBuf};
 use tar::Archive;
-
-pub fn git_clone(repo: &str, dir: &str, branch: Option<String>) -> AppResult<()> {
-    let repo_url = format!("git@github.com:{}.git", repo);
-    get_with_git(&repo_url, dir, branch)?;
+use tokio::sync::mpsc;
+
+/// Number of response chunks buffered between the download and extraction.
+const STREAM_BUFFER_CHUNKS: usize = 16;
+
+/// Clones the repo into `dir`, returning the SHA of the commit checked out.
+pub fn git_clone(
+    repo: &RepoMeta,
+    dir: &str,
+    git_ref: Option<GitRef>,
+    token: Option<&str>,
+    patterns: &[String],
+    filter: &PathFilter,
+) -> AppResult<String> {
+    // git@ sources always use SSH, others switch to HTTPS when a token is around
+    let https = token.is_some() && repo.protocol != "git@";
+    let repo_url = clone_url(repo, https);
+
+    let sparse = !patterns.is_empty()
+        && git::sparse_clone(&repo_url, dir, git_ref.as_ref(), token, patterns)?;
+    if !sparse {
+        git::shallow_clone(&repo_url, dir, git_ref.as_ref(), token, patterns)?;
+    }
+    let commit = git::head_commit(dir)?;
     fs::remove_dir_all(format!("{}/.git", &dir))?;
 
-    Ok(())
+    // sparse patterns only narrow the checkout, include and exclude rules
+    // are applied afterwards
+    filters::prune(Path::new(dir), filter)?;
+
+    Ok(commit)
 }
 
-pub async fn get_tarball(repo: &str, dir: &str, branch: Option<String>) -> AppResult<()> {
-    let stem_branch = if branch.is_some() {
-        format!("archive/refs/heads/{}.tar.gz", branch.unwrap_or_default())
-    } else {
-        "archive/HEAD.tar.gz".into()
+/// Downloads and extracts the repo into `dir`, returning the SHA of the
+/// commit archived when the forge records it.
+pub async fn get_tarball(
+    repo: &RepoMeta,
+    dir: &str,
+    git_ref: Option<GitRef>,
+    token: Option<&str>,
+    filter: &PathFilter,
+    symlinks: SymlinkPolicy,
+) -> AppResult<Option<String>> {
+    // Gitea has no archive alias for the primary branch, so ask its API
+    let git_ref = match (repo.forge, git_ref) {
+        (Forge::Gitea, None) => Some(GitRef::Branch(gitea_default_branch(repo, token).await?)),
+        (_, r) => r,
+    };
+    let repo_url = match token {
+        Some(_) => api_archive_url(repo, git_ref),
+        None => archive_url(repo, git_ref),
     };
 
-    let repo_url = format!("https://github.com/{}/{}", repo, stem_branch);
+    let res = match get(&repo_url, token).await {
+        // private repos look missing to anyone who can't see them
+        Err(Error::ClientWithStatus(reqwest::StatusCode::NOT_FOUND)) if token.is_some() => {
+            return Err(Error::NotFoundOrNoAccess(repo.url_stem.clone()))
+        }
+        res => res?,
+    };
 
-    let archive = download(&repo_url).await?;
-    unzip(dir, &archive)?;
+    stream_unzip(res, dir, filter, symlinks).await
+}
 
-    Ok(())
+/// Extracts the archive as it downloads, so memory use doesn't grow with
+/// the size of the archive.
+async fn stream_unzip(
+    mut res: reqwest::Response,
+    dir: &str,
+    filter: &PathFilter,
+    symlinks: SymlinkPolicy,
+) -> AppResult<Option<String>> {
+    let (tx, rx) = mpsc::channel(STREAM_BUFFER_CHUNKS);
+    let dest = dir.to_owned();
+    let filter = filter.clone();
+    let extract = tokio::task::spawn_blocking(move || {
+        unzip(&dest, ChannelReader::new(rx), &filter, symlinks)
+    });
+
+    let downloaded = loop {
+        match res.chunk().await {
+            Ok(Some(chunk)) => {
+                // the extractor hung up early, its result says why
+                if tx.send(chunk).await.is_err() {
+                    break Ok(());
+                }
+            }
+            Ok(None) => break Ok(()),
+            Err(e) => break Err(e),
+        }
+    };
+    drop(tx);
+
+    // a panicking extractor surfaces as an IO error, like any other failed read
+    let extracted = extract.await.map_err(io::Error::from)?;
+    downloaded?;
+    extracted
+}
+
+/// Reads response chunks handed over by the download task.
+struct ChannelReader {
+    rx: mpsc::Receiver<Bytes>,
+    chunk: Bytes,
+}
+
+impl ChannelReader {
+    fn new(rx: mpsc::Receiver<Bytes>) -> ChannelReader {
+        ChannelReader {
+            rx,
+            chunk: Bytes::new(),
+        }
+    }
+}
+
+impl Read for ChannelReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.chunk.is_empty() {
+            match self.rx.blocking_recv() {
+                Some(chunk) => self.chunk = chunk,
+                None => return Ok(0),
+            }
+        }
+
+        let n = buf.len().min(self.chunk.len());
+        buf[..n].copy_from_slice(&self.chunk.split_to(n));
+        Ok(n)
+    }
+}
+
+fn clone_url(repo: &RepoMeta, https: bool) -> String {
+    if https {
+        format!("{}/{}.git", repo.base_url, repo.url_stem)
+    } else {
+        format!("git@{}:{}.git", repo.host, repo.url_stem)
+    }
+}
+
+fn archive_url(repo: &RepoMeta, git_ref: Option<GitRef>) -> String {
+    match repo.forge {
+        Forge::GitHub => {
+            let stem_ref = match git_ref {
+                Some(GitRef::Branch(b)) => format!("archive/refs/heads/{}.tar.gz", b),
+                Some(GitRef::Rev(r)) => format!("archive/{}.tar.gz", r),
+                None => "archive/HEAD.tar.gz".into(),
+            };
+
+            format!("{}/{}/{}", repo.base_url, repo.url_stem, stem_ref)
+        }
+        Forge::GitLab => {
+            // project paths may be nested groups, so address them by encoded id
+            let url = format!(
+                "{}/api/v4/projects/{}/repository/archive.tar.gz",
+                repo.base_url,
+                encode_component(&repo.url_stem)
+            );
+
+            match git_ref {
+                Some(r) => format!("{}?sha={}", url, encode_component(r.name())),
+                None => url,
+            }
+        }
+        Forge::Gitea => format!(
+            "{}/{}/archive/{}.tar.gz",
+            repo.base_url,
+            repo.url_stem,
+            git_ref.as_ref().map(GitRef::name).unwrap_or("HEAD")
+        ),
+    }
+}
+
+/// Archive endpoints that honor an access token for private repos.
+fn api_archive_url(repo: &RepoMeta, git_ref: Option<GitRef>) -> String {
+    match repo.forge {
+        Forge::GitHub => {
+            let api = if repo.host == "github.com" {
+                "https://api.github.com".into()
+            } else {
+                format!("{}/api/v3", repo.base_url)
+            };
+            let url = format!("{}/repos/{}/tarball", api, repo.url_stem);
+
+            match git_ref {
+                Some(r) => format!("{}/{}", url, r.name()),
+                None => url,
+            }
+        }
+        // GitLab's archive endpoint is already part of its API
+        Forge::GitLab => archive_url(repo, git_ref),
+        Forge::Gitea => format!(
+            "{}/api/v1/repos/{}/archive/{}.tar.gz",
+            repo.base_url,
+            repo.url_stem,
+            git_ref.as_ref().map(GitRef::name).unwrap_or("HEAD")
+        ),
+    }
 }
 
-async fn download(url: &str) -> AppResult<Vec<u8>> {
+async fn gitea_default_branch(repo: &RepoMeta, token: Option<&str>) -> AppResult<String> {
+    let url = format!("{}/api/v1/repos/{}", repo.base_url, repo.url_stem);
+    let body = match get(&url, token).await {
+        Err(Error::ClientWithStatus(reqwest::StatusCode::NOT_FOUND)) if token.is_some() => {
+            return Err(Error::NotFoundOrNoAccess(repo.url_stem.clone()))
+        }
+        res => res?.text().await?,
+    };
+
+    let info: serde_json::Value =
+        serde_json::from_str(&body).map_err(|_| Error::BadResponse(url.clone()))?;
+    match info["default_branch"].as_str() {
+        Some(b) => Ok(b.into()),
+        None => Err(Error::BadResponse(url)),
+    }
+}
+
+fn encode_component(value: &str) -> String {
+    value
+        .bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => {
+                (b as char).to_string()
+            }
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+async fn get(url: &str, token: Option<&str>) -> AppResult<reqwest::Response> {
     let client = reqwest::Client::new();
 
-    let res_raw = client
-        .get(url)
-        .header(
-            reqwest::header::USER_AGENT,
-            reqwest::header::HeaderValue::from_static(env!("CARGO_PKG_NAME")),
-        )
-        .send()
-        .await?;
+    let mut req = client.get(url).header(
+        reqwest::header::USER_AGENT,
+        reqwest::header::HeaderValue::from_static(env!("CARGO_PKG_NAME")),
+    );
+    if let Some(t) = token {
+        req = req.bearer_auth(t);
+    }
+
+    let res_raw = req.send().await?;
 
     if res_raw.status().is_client_error() {
         return Err(Error::ClientWithStatus(res_raw.status()));
     }
-    let res_bytes = res_raw.bytes().await?;
-    let res_slice = res_bytes.as_ref().to_owned();
 
-    Ok(res_slice)
+    Ok(res_raw)
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum SymlinkPolicy {
+    Skip,
+    #[default]
+    Preserve,
+    /// Replaces links with copies of their targets, which must be in the tree.
+    Dereference,
 }
 
-fn get_with_git(url: &str, dest: &str, branch: Option<String>) -> AppResult<()> {
-    let options = ScriptOptions::new();
+impl std::str::FromStr for SymlinkPolicy {
+    type Err = String;
 
-    if branch.is_some() {
-        let (code, _, err) = run_script::run_script!(
-            r#"git clone -b "$1" --depth 1 "$2" "$3""#,
-            &vec![branch.unwrap_or_default(), url.into(), dest.into()],
-            options
-        )
-        .expect("Couldn't run script");
-        if code > 0 {
-            return Err(Error::BadClone(err));
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SymlinkPolicy::Skip),
+            "preserve" => Ok(SymlinkPolicy::Preserve),
+            "dereference" => Ok(SymlinkPolicy::Dereference),
+            _ => Err(format!("unknown symlink policy {}", s)),
         }
-    } else {
-        let (code, _, err) = run_script::run_script!(
-            r#"git clone --depth 1 "$1" "$2""#,
-            &vec![url.into(), dest.into()],
-            options
-        )
-        .expect("Couldn't run script");
-        if code > 0 {
-            return Err(Error::BadClone(err));
+    }
+}
+
+/// Extracts the archive into `dest`, returning the commit `git archive`
+/// noted in the global pax header, if there is one.
+fn unzip<R: Read>(
+    dest: &str,
+    res: R,
+    filter: &PathFilter,
+    symlinks: SymlinkPolicy,
+) -> AppResult<Option<String>> {
+    fs::create_dir_all(dest)?;
+    let root = fs::canonicalize(dest)?;
+
+    let tar = GzDecoder::new(res);
+    let mut archive = Archive::new(tar);
+    let files = archive.entries()?;
+    let mut links = Vec::new();
+    let mut commit = None;
+
+    for file in files {
+        // a truncated stream surfaces here rather than as a panic
+        let mut file = file?;
+        let entry_type = file.header().entry_type();
+
+        if entry_type.is_pax_global_extensions() {
+            for ext in file.pax_extensions()?.into_iter().flatten() {
+                let ext = ext?;
+                if ext.key() == Ok("comment") {
+                    commit = ext.value().ok().map(String::from);
+                }
+            }
+            continue;
         }
+
+        // trim off the root directory in the archive
+        let new_path = match archive_path(&file.path()?)? {
+            Some(p) => p,
+            None => continue,
+        };
+
+        // skip unwanted entries before anything is written
+        if !filter.matches(&new_path, entry_type.is_dir()) {
+            continue;
+        }
+
+        let target = root.join(&new_path);
+        if entry_type.is_dir() {
+            create_dir_within(&root, &target, &new_path)?;
+        } else if entry_type.is_file() || entry_type.is_contiguous() {
+            create_dir_within(&root, target.parent().unwrap_or(&root), &new_path)?;
+            file.unpack(&target)?;
+        } else if entry_type.is_symlink() {
+            if symlinks == SymlinkPolicy::Skip {
+                continue;
+            }
+            let link = file.link_name()?.unwrap_or_default().into_owned();
+            if !link_stays_within(&new_path, &link) {
+                return Err(Error::UnsafeArchiveEntry(new_path.display().to_string()));
+            }
+            create_dir_within(&root, target.parent().unwrap_or(&root), &new_path)?;
+            file.unpack(&target)?;
+            links.push(target);
+        } else if entry_type.is_hard_link() {
+            // hard links name their target from the archive root
+            let link = file.link_name()?.unwrap_or_default().into_owned();
+            let original = match archive_path(&link)? {
+                Some(p) => root.join(p),
+                None => return Err(Error::UnsafeArchiveEntry(new_path.display().to_string())),
+            };
+            // the target may be reached through links extracted earlier
+            match fs::canonicalize(original) {
+                Ok(o) if !o.starts_with(&root) => {
+                    return Err(Error::UnsafeArchiveEntry(new_path.display().to_string()))
+                }
+                Ok(o) if o.is_file() => {
+                    create_dir_within(&root, target.parent().unwrap_or(&root), &new_path)?;
+                    fs::copy(o, &target)?;
+                }
+                // missing from the archive, or filtered out
+                _ => missing_link_target(&new_path, symlinks)?,
+            }
+        }
+        // pax headers, devices and fifos have no place in a template
     }
 
+    // links can chain through each other, so check where they really end up
+    let mut resolved = Vec::new();
+    for link in links {
+        match fs::canonicalize(&link) {
+            Ok(r) if r.starts_with(&root) && !link.starts_with(&r) => {
+                resolved.push((link, Some(r)))
+            }
+            Ok(_) => {
+                let rel = link.strip_prefix(&root)?.display().to_string();
+                return Err(Error::UnsafeArchiveEntry(rel));
+            }
+            Err(_) => {
+                if symlinks == SymlinkPolicy::Dereference {
+                    missing_link_target(link.strip_prefix(&root)?, symlinks)?;
+                }
+                resolved.push((link, None))
+            }
+        }
+    }
+
+    if symlinks == SymlinkPolicy::Dereference {
+        for (link, target) in resolved {
+            fs::remove_file(&link)?;
+            if let Some(t) = target {
+                copy_recursively(&t, &link)?;
+            }
+        }
+    }
+
+    Ok(commit)
+}
+
+/// Dereferencing needs every link's target, so a missing one is an error
+/// then, and otherwise the link is left out with a warning.
+fn missing_link_target(path: &Path, symlinks: SymlinkPolicy) -> AppResult<()> {
+    if symlinks == SymlinkPolicy::Dereference {
+        return Err(Error::MissingLinkTarget(path.display().to_string()));
+    }
+    println!(
+        "{} skipped {}, as what it links to isn't in the archive",
+        style("warning:").yellow().bold(),
+        path.display()
+    );
+
     Ok(())
 }
 
-fn unzip(dest: &str, res: &[u8]) -> AppResult<()> {
-    let tar = GzDecoder::new(res);
-    let mut archive = Archive::new(tar);
-    let files = archive.entries()?.enumerate();
+/// Strips the archive's root directory from an entry path. Rejects absolute
+/// paths and `..` so nothing can be written outside the extraction dir.
+fn archive_path(path: &Path) -> AppResult<Option<PathBuf>> {
+    let mut components = path.components().filter(|c| *c != Component::CurDir);
+    let mut new_path = PathBuf::new();
 
-    for (_, file) in files {
-        let mut file = file.unwrap();
-        let file_path = file.path()?;
+    match components.next() {
+        Some(Component::Normal(_)) => {}
+        None => return Ok(None),
+        _ => return Err(Error::UnsafeArchiveEntry(path.display().to_string())),
+    }
+    for c in components {
+        match c {
+            Component::Normal(p) => new_path.push(p),
+            _ => return Err(Error::UnsafeArchiveEntry(path.display().to_string())),
+        }
+    }
 
-        // trim off the root directory in the archive
-        let mut components = file_path.components();
-        let root_dir = components.next().unwrap();
-        let new_path = file_path
-            .strip_prefix(root_dir)?
-            .to_str()
-            .unwrap()
-            .to_owned();
+    if new_path.as_os_str().is_empty() {
+        Ok(None)
+    } else {
+        Ok(Some(new_path))
+    }
+}
+
+/// Whether a relative symlink at `path` points somewhere inside the tree.
+pub fn link_stays_within(path: &Path, link: &Path) -> bool {
+    let mut depth = path.components().count().saturating_sub(1);
+
+    for c in link.components() {
+        match c {
+            Component::Normal(_) => depth += 1,
+            Component::CurDir => {}
+            Component::ParentDir if depth > 0 => depth -= 1,
+            _ => return false,
+        }
+    }
 
-        file.unpack(format!("{}/{}", dest, new_path)).unwrap();
+    true
+}
+
+/// Creates `dir` after checking that the part of it which already exists
+/// doesn't lead outside `root` through a symlink.
+fn create_dir_within(root: &Path, dir: &Path, entry: &Path) -> AppResult<()> {
+    let existing = dir
+        .ancestors()
+        .find(|a| fs::symlink_metadata(a).is_ok())
+        .unwrap_or(root);
+
+    // a dangling link could still be followed once its target appears
+    match fs::canonicalize(existing) {
+        Ok(p) if p.starts_with(root) => {}
+        _ => return Err(Error::UnsafeArchiveEntry(entry.display().to_string())),
+    }
+    fs::create_dir_all(dir)?;
+
+    Ok(())
+}
+
+fn copy_recursively(from: &Path, to: &Path) -> io::Result<()> {
+    if from.is_dir() {
+        fs::create_dir_all(to)?;
+        for entry in fs::read_dir(from)? {
+            let entry = entry?;
+            copy_recursively(&entry.path(), &to.join(entry.file_name()))?;
+        }
+    } else {
+        fs::copy(from, to)?;
     }
 
     Ok(())
@@ -104,6 +488,9 @@ fn unzip(dest: &str, res: &[u8]) -> AppResult<()> {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::config::Config;
+    use crate::directories::test_dir;
+    use repos::parse_repo_input;
 
     fn curr_ms() -> String {
         use std::time::{SystemTime, UNIX_EPOCH};
@@ -121,7 +508,14 @@ mod tests {
 
         let repo = "bradyjoslin/rsieve";
         let dir = &format!("{}-{}", "it_gets_github_git_repos", curr_ms());
-        let res = git_clone(repo, dir, None);
+        let res = git_clone(
+            &parse_repo_input(repo, &Config::default()).unwrap(),
+            dir,
+            None,
+            None,
+            &[],
+            &PathFilter::new(&None, None).unwrap(),
+        );
 
         assert_eq!(res.is_ok(), true);
 
@@ -144,7 +538,15 @@ mod tests {
 
         let repo = "bradyjoslin/sharewifi";
         let dir = &format!("{}-{}", "it_gets_github_tarball_repos", curr_ms());
-        let res = get_tarball(repo, dir, None).await;
+        let res = get_tarball(
+            &parse_repo_input(repo, &Config::default()).unwrap(),
+            dir,
+            None,
+            None,
+            &PathFilter::new(&None, None).unwrap(),
+            SymlinkPolicy::Preserve,
+        )
+        .await;
 
         assert_eq!(res.is_ok(), true);
 
@@ -165,7 +567,15 @@ mod tests {
     async fn it_fails_nonexist_github_tarball_repos() {
         let repo = "bradyjoslin/sharewifisss";
         let dir = "it_fails_nonexist_github_tarball_repos";
-        let res = get_tarball(repo, dir, None).await;
+        let res = get_tarball(
+            &parse_repo_input(repo, &Config::default()).unwrap(),
+            dir,
+            None,
+            None,
+            &PathFilter::new(&None, None).unwrap(),
+            SymlinkPolicy::Preserve,
+        )
+        .await;
 
         assert_eq!(res.is_err(), true);
     }
@@ -175,8 +585,364 @@ mod tests {
     fn it_fails_nonexist_gets_github_git_repos() {
         let repo = "bradyjoslin/sharewifisss";
         let dir = "it_fails_nonexist_gets_github_git_repos";
-        let res = git_clone(repo, dir, None);
+        let res = git_clone(
+            &parse_repo_input(repo, &Config::default()).unwrap(),
+            dir,
+            None,
+            None,
+            &[],
+            &PathFilter::new(&None, None).unwrap(),
+        );
 
         assert_eq!(res.is_err(), true);
     }
+
+    #[test]
+    fn it_builds_gitlab_archive_urls() {
+        let repo = parse_repo_input("gitlab:group/subgroup/project", &Config::default()).unwrap();
+
+        assert_eq!(
+            archive_url(&repo, Some(GitRef::Branch("feature/one".into()))),
+            "https://gitlab.com/api/v4/projects/group%2Fsubgroup%2Fproject/repository/archive.tar.gz?sha=feature%2Fone"
+        );
+        assert_eq!(
+            clone_url(&repo, false),
+            "git@gitlab.com:group/subgroup/project.git"
+        );
+    }
+
+    #[test]
+    fn it_builds_self_hosted_urls() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+
+            [hosts."code.corp.example"]
+            forge = "gitea"
+            "#,
+        )
+        .unwrap();
+
+        let ghe = parse_repo_input("ghe.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            archive_url(&ghe, None),
+            "https://ghe.corp.example/team/repo/archive/HEAD.tar.gz"
+        );
+        assert_eq!(clone_url(&ghe, false), "git@ghe.corp.example:team/repo.git");
+        assert_eq!(
+            clone_url(&ghe, true),
+            "https://ghe.corp.example/team/repo.git"
+        );
+
+        let gitea = parse_repo_input("code.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            archive_url(&gitea, Some(GitRef::Branch("main".into()))),
+            "https://code.corp.example/team/repo/archive/main.tar.gz"
+        );
+    }
+
+    #[test]
+    fn it_builds_github_ref_urls() {
+        let repo = parse_repo_input("bradyjoslin/sharewifi", &Config::default()).unwrap();
+
+        assert_eq!(
+            archive_url(&repo, Some(GitRef::Branch("main".into()))),
+            "https://github.com/bradyjoslin/sharewifi/archive/refs/heads/main.tar.gz"
+        );
+        assert_eq!(
+            archive_url(&repo, Some(GitRef::Rev("v1.2.0".into()))),
+            "https://github.com/bradyjoslin/sharewifi/archive/v1.2.0.tar.gz"
+        );
+    }
+
+    #[test]
+    fn it_builds_authenticated_urls() {
+        let repo = parse_repo_input("bradyjoslin/sharewifi", &Config::default()).unwrap();
+        assert_eq!(
+            api_archive_url(&repo, Some(GitRef::Rev("v1.2.0".into()))),
+            "https://api.github.com/repos/bradyjoslin/sharewifi/tarball/v1.2.0"
+        );
+
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+            "#,
+        )
+        .unwrap();
+        let ghe = parse_repo_input("ghe.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            api_archive_url(&ghe, None),
+            "https://ghe.corp.example/api/v3/repos/team/repo/tarball"
+        );
+    }
+
+    const SAMPLE_COMMIT: &str = "3be9c1a5d0e6c2f1b7a8e9d4c3b2a1f0e9d8c7b6";
+
+    fn sample_archive() -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        let mut header = tar::Header::new_ustar();
+        header.set_entry_type(tar::EntryType::XGlobalHeader);
+        let pax = format!("52 comment={}\n", SAMPLE_COMMIT);
+        header.set_size(pax.len() as u64);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "pax_global_header", pax.as_bytes())
+            .unwrap();
+        for (path, contents) in [
+            ("repo-main/README.md", "readme"),
+            ("repo-main/.github/workflows/ci.yml", "on: push"),
+            ("repo-main/src/main.rs", "fn main() {}"),
+        ] {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(contents.len() as u64);
+            header.set_mode(0o644);
+            header.set_cksum();
+            builder
+                .append_data(&mut header, path, contents.as_bytes())
+                .unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    #[test]
+    fn it_extracts_only_matching_entries() {
+        let dir = test_dir("it_extracts_only_matching_entries")
+            .display()
+            .to_string();
+        let filter = PathFilter::new(&None, Some(".github/*")).unwrap();
+
+        let commit = unzip(
+            &dir,
+            &sample_archive()[..],
+            &filter,
+            SymlinkPolicy::Preserve,
+        )
+        .expect("should extract archive");
+
+        assert_eq!(commit.as_deref(), Some(SAMPLE_COMMIT));
+        assert!(Path::new(&dir).join(".github/workflows/ci.yml").exists());
+        assert!(!Path::new(&dir).join("README.md").exists());
+        assert!(!Path::new(&dir).join("src").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn it_reads_chunks_across_the_channel() {
+        let archive = sample_archive();
+        let (tx, rx) = mpsc::channel(2);
+
+        let reader = tokio::task::spawn_blocking(move || {
+            let mut out = Vec::new();
+            ChannelReader::new(rx).read_to_end(&mut out).unwrap();
+            out
+        });
+        for chunk in archive.chunks(7) {
+            tx.send(Bytes::copy_from_slice(chunk)).await.unwrap();
+        }
+        drop(tx);
+
+        assert_eq!(reader.await.unwrap(), archive);
+    }
+
+    fn archive_of(entries: &[(&str, &str, Option<&str>)]) -> Vec<u8> {
+        let entries: Vec<_> = entries
+            .iter()
+            .map(|(path, contents, link)| {
+                (*path, *contents, link.map(|l| (tar::EntryType::Symlink, l)))
+            })
+            .collect();
+        archive_with_links(&entries)
+    }
+
+    /// Path, contents, and the kind and name of the link it is, if any.
+    type Entry<'a> = (&'a str, &'a str, Option<(tar::EntryType, &'a str)>);
+
+    fn archive_with_links(entries: &[Entry]) -> Vec<u8> {
+        use flate2::{write::GzEncoder, Compression};
+
+        let mut builder = tar::Builder::new(GzEncoder::new(Vec::new(), Compression::default()));
+        for (path, contents, link) in entries {
+            let mut header = tar::Header::new_gnu();
+            // write the raw name so unsafe paths make it into the archive
+            header.as_old_mut().name[..path.len()].copy_from_slice(path.as_bytes());
+            header.set_mode(0o644);
+            match link {
+                Some((kind, l)) => {
+                    header.set_entry_type(*kind);
+                    header.set_link_name_literal(l).unwrap();
+                    header.set_size(0);
+                }
+                None => header.set_size(contents.len() as u64),
+            }
+            header.set_cksum();
+            builder.append(&header, contents.as_bytes()).unwrap();
+        }
+
+        builder.into_inner().unwrap().finish().unwrap()
+    }
+
+    fn extract(
+        test_name: &str,
+        archive: &[u8],
+        symlinks: SymlinkPolicy,
+    ) -> (PathBuf, AppResult<Option<String>>) {
+        let dir = test_dir(test_name);
+        let filter = PathFilter::new(&None, None).unwrap();
+        let res = unzip(&dir.display().to_string(), archive, &filter, symlinks);
+
+        (dir, res)
+    }
+
+    #[test]
+    fn it_rejects_parent_entries() {
+        let archive = archive_of(&[("repo-main/../../evil.txt", "evil", None)]);
+        let (dir, res) = extract(
+            "it_rejects_parent_entries",
+            &archive,
+            SymlinkPolicy::Preserve,
+        );
+
+        assert!(matches!(res, Err(Error::UnsafeArchiveEntry(_))));
+        assert!(!dir.parent().unwrap().join("evil.txt").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_escaping_symlinks() {
+        let archive = archive_of(&[
+            ("repo-main/etc", "", Some("../../../etc")),
+            ("repo-main/etc/passwd", "evil", None),
+        ]);
+        let (dir, res) = extract(
+            "it_rejects_escaping_symlinks",
+            &archive,
+            SymlinkPolicy::Preserve,
+        );
+
+        assert!(matches!(res, Err(Error::UnsafeArchiveEntry(_))));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_symlinks_escaping_through_other_links() {
+        let archive = archive_of(&[
+            ("repo-main/here", "", Some(".")),
+            ("repo-main/up", "", Some("here/../outside")),
+            ("repo-main/up/evil.txt", "evil", None),
+        ]);
+        let (dir, res) = extract(
+            "it_rejects_symlinks_escaping_through_other_links",
+            &archive,
+            SymlinkPolicy::Preserve,
+        );
+
+        assert!(matches!(res, Err(Error::UnsafeArchiveEntry(_))));
+        assert!(!dir.parent().unwrap().join("outside").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_hard_links_escaping_through_symlinks() {
+        use tar::EntryType::{Link, Symlink};
+
+        let base = test_dir("it_rejects_hard_links_escaping_through_symlinks");
+        fs::create_dir_all(&base).unwrap();
+        fs::write(base.join("secret.txt"), "secret").unwrap();
+
+        // each link stays in the tree on its own, but l2 ends up at `base`
+        let archive = archive_with_links(&[
+            ("repo-main/d/l", "", Some((Symlink, ".."))),
+            ("repo-main/l2", "", Some((Symlink, "d/l/.."))),
+            (
+                "repo-main/stolen.txt",
+                "",
+                Some((Link, "repo-main/l2/secret.txt")),
+            ),
+        ]);
+        let dir = base.join("out");
+        let filter = PathFilter::new(&None, None).unwrap();
+        let res = unzip(
+            &dir.display().to_string(),
+            &archive[..],
+            &filter,
+            SymlinkPolicy::Preserve,
+        );
+
+        assert!(matches!(res, Err(Error::UnsafeArchiveEntry(_))));
+        assert!(!dir.join("stolen.txt").exists());
+        fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_reports_hard_links_to_missing_files() {
+        use tar::EntryType::Link;
+
+        let archive = archive_with_links(&[
+            ("repo-main/README.md", "readme", None),
+            (
+                "repo-main/docs/index.md",
+                "",
+                Some((Link, "repo-main/gone.md")),
+            ),
+        ]);
+
+        let (dir, res) = extract(
+            "it_skips_hard_links_to_missing_files",
+            &archive,
+            SymlinkPolicy::Preserve,
+        );
+        assert!(res.is_ok());
+        assert!(dir.join("README.md").exists());
+        assert!(!dir.join("docs/index.md").exists());
+        fs::remove_dir_all(dir).unwrap();
+
+        let (dir, res) = extract(
+            "it_rejects_hard_links_to_missing_files",
+            &archive,
+            SymlinkPolicy::Dereference,
+        );
+        assert!(matches!(res, Err(Error::MissingLinkTarget(p)) if p == "docs/index.md"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_applies_symlink_policies() {
+        let archive = archive_of(&[
+            ("repo-main/README.md", "readme", None),
+            ("repo-main/docs/index.md", "", Some("../README.md")),
+        ]);
+
+        let (dir, res) = extract("it_skips_symlinks", &archive, SymlinkPolicy::Skip);
+        assert!(res.is_ok());
+        assert!(!dir.join("docs/index.md").exists());
+        fs::remove_dir_all(dir).unwrap();
+
+        let (dir, res) = extract("it_preserves_symlinks", &archive, SymlinkPolicy::Preserve);
+        assert!(res.is_ok());
+        assert!(fs::symlink_metadata(dir.join("docs/index.md"))
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        fs::remove_dir_all(dir).unwrap();
+
+        let (dir, res) = extract(
+            "it_dereferences_symlinks",
+            &archive,
+            SymlinkPolicy::Dereference,
+        );
+        assert!(res.is_ok());
+        let meta = fs::symlink_metadata(dir.join("docs/index.md")).unwrap();
+        assert!(meta.file_type().is_file());
+        assert_eq!(
+            fs::read_to_string(dir.join("docs/index.md")).unwrap(),
+            "readme"
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
 }
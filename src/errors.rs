@@ -1,10 +1,12 @@
 // Provides custom errors for good UX
+use crate::config;
+use crate::lockfile;
 use console::style;
 use std::fmt;
 
 pub enum Error {
     BadInput,
-    BadHost,
+    BadHost(String),
     BadOwner,
     BadRepo,
     ClientTimeout,
@@ -14,8 +16,27 @@ pub enum Error {
     BadStripPrefix,
     DesinationNotEmpty(String),
     NoMatchingFiles,
-    BadClone(String),
     BadGit(git2::ErrorCode, String),
+    BadConfig(String),
+    BadResponse(String),
+    BadSubdirectory(String),
+    NotFoundOrNoAccess(String),
+    BadFilter(String),
+    UnsafeArchiveEntry(String),
+    MissingLinkTarget(String),
+    LinkOutsideDestination(String),
+    PathCollision(String, String, String),
+    BadRenderedName(String, String),
+    BadManifest(String),
+    MissingVariable(String, Option<String>),
+    BadVariable(String, String),
+    BadProperties(String, String),
+    UnknownTemplate(String, Vec<String>),
+    FileExists(String),
+    NotInteractive,
+    BadLock(String),
+    NoLock(String),
+    LockedElsewhere(String, String),
 }
 
 pub type AppResult<T> = Result<T, Error>;
@@ -24,20 +45,115 @@ impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::BadInput => write!(f, "Invalid source specified."),
-            Error::BadHost => write!(f, "Only GitHub is supported."),
+            Error::BadHost(host) => match config::config_path() {
+                Some(path) => write!(
+                    f,
+                    "Unknown host {}. Register it in {}.",
+                    host,
+                    path.display()
+                ),
+                None => write!(
+                    f,
+                    "Unknown host {}. Register it in the rsieve config file.",
+                    host
+                ),
+            },
             Error::BadOwner => write!(f, "No owner detected in source."),
             Error::BadRepo => write!(f, "No repo detected in source."),
-            Error::DesinationNotEmpty(dest) => write!(f, "Destination {} is not empty.", dest),
+            Error::DesinationNotEmpty(dest) => {
+                write!(
+                    f,
+                    "Destination {} is not empty. Try --merge or --on-conflict.",
+                    dest
+                )
+            }
             Error::Io(k) => write!(f, "IO error: {:?}", k),
             Error::ClientTimeout => write!(f, "Timeout during request"),
             Error::ClientWithStatus(status) => write!(f, "Got status code: {}.", status),
             Error::ClientOther => write!(f, "Unknown client error."),
             Error::BadStripPrefix => write!(f, "Strip prefix error writing files."),
             Error::NoMatchingFiles => write!(f, "No matching files found for filter."),
-            Error::BadClone(err) => write!(f, "Error cloning repo.\n{}", err),
             Error::BadGit(code, msg) => {
                 write!(f, "Git error: {:?} - {}", code, msg)
             }
+            Error::BadConfig(msg) => write!(f, "Invalid configuration.\n{}", msg),
+            Error::BadResponse(url) => write!(f, "Unexpected response from {}.", url),
+            Error::BadSubdirectory(dir) => write!(f, "Subdirectory {} not found in repo.", dir),
+            Error::BadFilter(msg) => write!(f, "Invalid filter {}.", msg),
+            Error::PathCollision(dest, first, second) => write!(
+                f,
+                "Both {} and {} would be written to {}. Try --keep-paths.",
+                first, second, dest
+            ),
+            Error::BadRenderedName(name, rendered) => write!(
+                f,
+                "{} renders to {:?}, which isn't a file name. Check the variables it uses.",
+                name, rendered
+            ),
+            Error::UnsafeArchiveEntry(path) => {
+                write!(
+                    f,
+                    "Archive entry {} would be written outside the repo.",
+                    path
+                )
+            }
+            Error::MissingLinkTarget(path) => write!(
+                f,
+                "Archive entry {} links to a file that isn't in the archive or was filtered out.",
+                path
+            ),
+            Error::LinkOutsideDestination(path) => write!(
+                f,
+                "{} would link outside the destination. Try --symlinks dereference or skip.",
+                path
+            ),
+            Error::BadManifest(msg) => write!(f, "Invalid template manifest.\n{}", msg),
+            Error::MissingVariable(name, description) => {
+                write!(f, "No value for template variable {}", name)?;
+                if let Some(d) = description {
+                    write!(f, " ({})", d)?;
+                }
+                write!(f, ". Try --var {}=<value>.", name)
+            }
+            Error::BadVariable(name, msg) => {
+                write!(f, "Invalid value for template variable {}: {}.", name, msg)
+            }
+            Error::BadProperties(file, msg) => write!(f, "Invalid {}.\n{}", file, msg),
+            Error::UnknownTemplate(id, ids) if ids.is_empty() => write!(
+                f,
+                "No workflow template {}, and no workflow-templates directory in repo.",
+                id
+            ),
+            Error::UnknownTemplate(id, ids) => write!(
+                f,
+                "No workflow template {}. Try one of: {}.",
+                id,
+                ids.join(", ")
+            ),
+            Error::FileExists(path) => write!(f, "{} already exists.", path),
+            Error::BadLock(msg) => write!(f, "Invalid {}.\n{}", lockfile::LOCK_FILE, msg),
+            Error::NoLock(dest) => write!(
+                f,
+                "No {} in {}. Copy with --lock to record one.",
+                lockfile::LOCK_FILE,
+                dest
+            ),
+            Error::LockedElsewhere(path, source) => write!(
+                f,
+                "{} already records a different copy, of {}. Lock this one in another destination, or remove it first.",
+                path, source
+            ),
+            Error::NotInteractive => write!(
+                f,
+                "Can't prompt about conflicts without a terminal. Try another --on-conflict."
+            ),
+            Error::NotFoundOrNoAccess(repo) => {
+                write!(
+                    f,
+                    "Repo {} not found or no access with the given token.",
+                    repo
+                )
+            }
         }
     }
 }
@@ -0,0 +1,309 @@
+// Decides which repo paths are wanted before they are written to disk
+use crate::errors;
+use crate::manifest::MANIFEST_FILE;
+use errors::{AppResult, Error};
+use glob::{MatchOptions, Pattern};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+#[derive(Clone)]
+pub struct PathFilter {
+    pattern: Option<Pattern>,
+    subdir: Option<PathBuf>,
+    manifest: PathBuf,
+    include: Option<Gitignore>,
+    exclude: Option<Gitignore>,
+}
+
+impl PathFilter {
+    /// Builds a filter from the source subdirectory and glob filter, both
+    /// relative to the repo root. Matches everything when neither is given.
+    pub fn new(subdir: &Option<String>, filter: Option<&str>) -> AppResult<PathFilter> {
+        let full = match (subdir, filter) {
+            (Some(s), Some(f)) => Some(format!("{}/{}", s, f.trim_start_matches('/'))),
+            (Some(s), None) => Some(s.to_owned()),
+            (None, Some(f)) => Some(f.trim_start_matches('/').to_owned()),
+            (None, None) => None,
+        };
+
+        let pattern = match full.as_deref().map(Pattern::new) {
+            Some(Ok(p)) => Some(p),
+            Some(Err(e)) => {
+                return Err(Error::BadFilter(format!(
+                    "{}: {}",
+                    full.unwrap_or_default(),
+                    e.msg
+                )))
+            }
+            None => None,
+        };
+
+        let subdir = subdir.as_ref().map(PathBuf::from);
+        let manifest = match &subdir {
+            Some(s) => s.join(MANIFEST_FILE),
+            None => PathBuf::from(MANIFEST_FILE),
+        };
+
+        Ok(PathFilter {
+            pattern,
+            subdir,
+            manifest,
+            include: None,
+            exclude: None,
+        })
+    }
+
+    /// Adds gitignore-style include and exclude rules, relative to the
+    /// subdirectory if there is one. With includes, only paths matching one
+    /// of them are kept; `!` negates, as in a .gitignore.
+    pub fn with_rules(
+        mut self,
+        includes: &[String],
+        excludes: &[String],
+        ignore_file: Option<&Path>,
+    ) -> AppResult<PathFilter> {
+        if !includes.is_empty() {
+            self.include = Some(gitignore(includes, None)?);
+        }
+        if !excludes.is_empty() || ignore_file.is_some() {
+            self.exclude = Some(gitignore(excludes, ignore_file)?);
+        }
+
+        Ok(self)
+    }
+
+    /// Whether a path, relative to the repo root, matches the filter itself
+    /// or sits inside a directory that does. The template manifest always
+    /// matches.
+    pub fn matches(&self, path: &Path, is_dir: bool) -> bool {
+        if path == self.manifest {
+            return true;
+        }
+
+        if let Some(pattern) = &self.pattern {
+            let options = MatchOptions {
+                require_literal_separator: true,
+                ..MatchOptions::new()
+            };
+            let matched = path
+                .ancestors()
+                .filter(|a| !a.as_os_str().is_empty())
+                .any(|a| pattern.matches_path_with(a, options));
+
+            if !matched {
+                return false;
+            }
+        }
+
+        let rel_path = match &self.subdir {
+            Some(s) => match path.strip_prefix(s) {
+                Ok(r) => r,
+                Err(_) => return false,
+            },
+            None => path,
+        };
+        if rel_path.as_os_str().is_empty() {
+            return true;
+        }
+
+        if let Some(include) = &self.include {
+            if !include
+                .matched_path_or_any_parents(rel_path, is_dir)
+                .is_ignore()
+            {
+                return false;
+            }
+        }
+        if let Some(exclude) = &self.exclude {
+            if exclude
+                .matched_path_or_any_parents(rel_path, is_dir)
+                .is_ignore()
+            {
+                return false;
+            }
+        }
+
+        true
+    }
+}
+
+fn gitignore(lines: &[String], file: Option<&Path>) -> AppResult<Gitignore> {
+    let mut builder = GitignoreBuilder::new(".");
+    if let Some(f) = file {
+        if !f.is_file() {
+            return Err(Error::BadFilter(format!("{}: file not found", f.display())));
+        }
+        if let Some(e) = builder.add(f) {
+            return Err(Error::BadFilter(format!("{}: {}", f.display(), e)));
+        }
+    }
+    for line in lines {
+        if let Err(e) = builder.add_line(None, line) {
+            return Err(Error::BadFilter(format!("{}: {}", line, e)));
+        }
+    }
+
+    builder.build().map_err(|e| Error::BadFilter(e.to_string()))
+}
+
+/// Removes whatever under `root` the filter doesn't match, for fetches
+/// that can't filter as they write. Directories left empty go as well.
+pub fn prune(root: &Path, filter: &PathFilter) -> AppResult<()> {
+    prune_dir(root, Path::new(""), filter)?;
+
+    Ok(())
+}
+
+fn prune_dir(root: &Path, rel_dir: &Path, filter: &PathFilter) -> AppResult<bool> {
+    let mut kept_any = false;
+
+    for entry in fs::read_dir(root.join(rel_dir))? {
+        let entry = entry?;
+        let rel_path = rel_dir.join(entry.file_name());
+
+        if entry.file_type()?.is_dir() {
+            if prune_dir(root, &rel_path, filter)? {
+                kept_any = true;
+            } else {
+                fs::remove_dir_all(root.join(&rel_path))?;
+            }
+        } else if filter.matches(&rel_path, false) {
+            kept_any = true;
+        } else {
+            fs::remove_file(root.join(&rel_path))?;
+        }
+    }
+
+    Ok(kept_any)
+}
+
+/// Paths under `root` the filter matches, without descending into
+/// matching directories.
+pub fn find(root: &Path, filter: &PathFilter) -> AppResult<Vec<PathBuf>> {
+    let mut found = Vec::new();
+    find_in(root, Path::new(""), filter, &mut found)?;
+    found.sort();
+
+    Ok(found)
+}
+
+fn find_in(
+    root: &Path,
+    rel_dir: &Path,
+    filter: &PathFilter,
+    found: &mut Vec<PathBuf>,
+) -> AppResult<()> {
+    for entry in fs::read_dir(root.join(rel_dir))? {
+        let entry = entry?;
+        let rel_path = rel_dir.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+
+        if filter.matches(&rel_path, is_dir) {
+            found.push(rel_path);
+        } else if is_dir {
+            find_in(root, &rel_path, filter, found)?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    #[test]
+    fn it_matches_everything_without_filters() {
+        let filter = PathFilter::new(&None, None).unwrap();
+
+        assert!(filter.matches(Path::new("src/main.rs"), false));
+    }
+
+    #[test]
+    fn it_matches_inside_matching_directories() {
+        let filter = PathFilter::new(&None, Some(".github/*")).unwrap();
+
+        assert!(filter.matches(Path::new(".github/workflows/ci.yml"), false));
+        assert!(!filter.matches(Path::new(".github"), true));
+        assert!(!filter.matches(Path::new("src/.github/ci.yml"), false));
+    }
+
+    #[test]
+    fn it_matches_within_subdirectories() {
+        let filter = PathFilter::new(&Some("templates".into()), Some("*.md")).unwrap();
+
+        assert!(filter.matches(Path::new("templates/README.md"), false));
+        assert!(!filter.matches(Path::new("templates/web/README.md"), false));
+        assert!(!filter.matches(Path::new("README.md"), false));
+        assert!(filter.matches(Path::new("templates/rsieve.toml"), false));
+    }
+
+    #[test]
+    fn it_rejects_bad_patterns() {
+        assert!(PathFilter::new(&None, Some("src/***")).is_err());
+    }
+
+    fn rules(includes: &[&str], excludes: &[&str]) -> PathFilter {
+        let includes: Vec<String> = includes.iter().map(|i| i.to_string()).collect();
+        let excludes: Vec<String> = excludes.iter().map(|e| e.to_string()).collect();
+
+        PathFilter::new(&None, None)
+            .unwrap()
+            .with_rules(&includes, &excludes, None)
+            .unwrap()
+    }
+
+    #[test]
+    fn it_applies_gitignore_rules() {
+        let filter = rules(&["templates/"], &["*.snap", "tests/"]);
+
+        assert!(filter.matches(Path::new("templates/web/index.html"), false));
+        assert!(!filter.matches(Path::new("templates/web/index.snap"), false));
+        assert!(!filter.matches(Path::new("templates/tests/web.rs"), false));
+        assert!(!filter.matches(Path::new("README.md"), false));
+    }
+
+    #[test]
+    fn it_negates_and_anchors_rules() {
+        let filter = rules(&["/*.md", "!/CHANGELOG.md"], &[]);
+
+        assert!(filter.matches(Path::new("README.md"), false));
+        assert!(!filter.matches(Path::new("CHANGELOG.md"), false));
+        assert!(!filter.matches(Path::new("docs/guide.md"), false));
+
+        let filter = rules(&["docs/**/*.md"], &["build"]);
+        assert!(filter.matches(Path::new("docs/a/b/guide.md"), false));
+        assert!(!filter.matches(Path::new("docs/build/guide.md"), false));
+    }
+
+    #[test]
+    fn it_applies_rules_within_subdirectories() {
+        let filter = PathFilter::new(&Some("templates".into()), None)
+            .unwrap()
+            .with_rules(&[], &["/tests/".into()], None)
+            .unwrap();
+
+        assert!(filter.matches(Path::new("templates/src/tests/web.rs"), false));
+        assert!(!filter.matches(Path::new("templates/tests/web.rs"), false));
+        assert!(!filter.matches(Path::new("other/src/web.rs"), false));
+    }
+
+    #[test]
+    fn it_prunes_unmatched_paths() {
+        let root = test_dir("it_prunes_unmatched_paths");
+        for file in ["templates/a.txt", "templates/a.snap", "src/main.rs"] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+
+        prune(&root, &rules(&["templates/"], &["*.snap"])).unwrap();
+
+        assert!(root.join("templates/a.txt").exists());
+        assert!(!root.join("templates/a.snap").exists());
+        assert!(!root.join("src").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,9 +1,38 @@
+use crate::directories;
 use crate::errors;
-use errors::AppResult;
-use git2::Repository;
+use crate::manifest::MANIFEST_FILE;
+use crate::repos::GitRef;
+use base64::Engine;
+use errors::{AppResult, Error};
+use git2::build::CheckoutBuilder;
+use git2::{
+    AutotagOption, Cred, CredentialType, Direction, ErrorCode, FetchOptions, RemoteCallbacks,
+    Repository,
+};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// What to ask the remote for once a ref has been resolved.
+enum Want {
+    /// A full ref name advertised by the remote.
+    Name(String),
+    /// A full commit SHA.
+    Commit(String),
+    /// An abbreviated SHA, which needs history to resolve.
+    Search(String),
+}
+
+/// Finds the repo enclosing `path`, which doesn't have to exist yet.
+fn discover(path: &str) -> AppResult<Repository> {
+    let path = directories::resolve(path);
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(&path);
+
+    Ok(Repository::discover(existing)?)
+}
 
 pub fn default_branch(path: &str) -> AppResult<String> {
-    Ok(Repository::discover(path)?
+    Ok(discover(path)?
         .find_reference("refs/remotes/origin/HEAD")?
         .symbolic_target()
         .unwrap_or_default()
@@ -13,9 +42,340 @@ pub fn default_branch(path: &str) -> AppResult<String> {
         .into())
 }
 
+/// Branches listed under `rsieve.protectedBranch` in the repo's git config,
+/// one per entry or comma separated.
+pub fn protected_branches(path: &str) -> AppResult<Vec<String>> {
+    let config = discover(path)?.config()?;
+    let mut branches = Vec::new();
+
+    config
+        .multivar("rsieve.protectedbranch", None)?
+        .for_each(|entry| {
+            let names = entry.value().unwrap_or_default().split(',');
+            branches.extend(
+                names
+                    .map(str::trim)
+                    .filter(|b| !b.is_empty())
+                    .map(String::from),
+            );
+        })?;
+
+    Ok(branches)
+}
+
+/// SHA of the commit checked out in the repo at `path`.
+pub fn head_commit(path: &str) -> AppResult<String> {
+    Ok(Repository::open(path)?
+        .head()?
+        .peel_to_commit()?
+        .id()
+        .to_string())
+}
+
+pub fn origin_url(path: &str) -> AppResult<String> {
+    Ok(discover(path)?
+        .find_remote("origin")?
+        .url()
+        .unwrap_or_default()
+        .into())
+}
+
+/// Translates the source subdirectory and glob filter into sparse-checkout
+/// patterns. Empty when the whole tree is wanted. A filter still brings
+/// along the template manifest.
+pub fn sparse_patterns(subdir: &Option<String>, filter: Option<&str>) -> Vec<String> {
+    let prefix = match subdir {
+        Some(s) => format!("/{}", s),
+        None => "".into(),
+    };
+
+    match filter {
+        Some(f) => vec![
+            format!("{}/{}", prefix, f.trim_start_matches('/')),
+            format!("{}/{}", prefix, MANIFEST_FILE),
+        ],
+        None if subdir.is_some() => vec![format!("{}/", prefix)],
+        None => Vec::new(),
+    }
+}
+
+/// Makes a blobless, depth 1 clone with a sparse checkout using the git
+/// binary, since libgit2 can't do partial clones. Returns false, after
+/// removing anything written to `dest`, when git is missing, too old or
+/// fails, or the ref is an abbreviated SHA, so the caller can fall back to
+/// `shallow_clone`.
+pub fn sparse_clone(
+    url: &str,
+    dest: &str,
+    git_ref: Option<&GitRef>,
+    token: Option<&str>,
+    patterns: &[String],
+) -> AppResult<bool> {
+    // partial clones need 2.27, and passing a token through the environment
+    // needs 2.31
+    let needed = if token.is_some() { (2, 31) } else { (2, 27) };
+    if git_version() < needed {
+        return Ok(false);
+    }
+
+    let (branch, commit) = match git_ref {
+        None => (None, None),
+        Some(GitRef::Branch(b)) => (Some(b.as_str()), None),
+        Some(GitRef::Rev(r)) if is_hex(r) && r.len() == 40 => (None, Some(r.as_str())),
+        Some(GitRef::Rev(r)) if is_hex(r) => return Ok(false),
+        // clone --branch takes tags as well
+        Some(GitRef::Rev(r)) => (Some(r.as_str()), None),
+    };
+
+    if sparse_checkout(url, dest, branch, commit, token, patterns).is_err() {
+        if Path::new(dest).exists() {
+            fs::remove_dir_all(dest)?;
+        }
+        return Ok(false);
+    }
+
+    Ok(true)
+}
+
+fn sparse_checkout(
+    url: &str,
+    dest: &str,
+    branch: Option<&str>,
+    commit: Option<&str>,
+    token: Option<&str>,
+    patterns: &[String],
+) -> AppResult<()> {
+    let mut clone = vec![
+        "clone",
+        "--quiet",
+        "--filter=blob:none",
+        "--no-checkout",
+        "--depth",
+        "1",
+    ];
+    if let Some(b) = branch {
+        clone.extend(["--branch", b]);
+    }
+    clone.extend(["--", url, dest]);
+    run_git(&clone, token)?;
+
+    fs::write(
+        format!("{}/.git/info/sparse-checkout", dest),
+        patterns.join("\n") + "\n",
+    )?;
+    run_git(
+        &["-C", dest, "config", "core.sparseCheckout", "true"],
+        token,
+    )?;
+
+    let target = match commit {
+        Some(sha) => {
+            run_git(
+                &[
+                    "-C", dest, "fetch", "--quiet", "--depth", "1", "origin", sha,
+                ],
+                token,
+            )?;
+            "FETCH_HEAD"
+        }
+        None => "HEAD",
+    };
+    // only blobs matching the sparse patterns are fetched here
+    run_git(&["-C", dest, "read-tree", "-mu", target], token)?;
+    if commit.is_some() {
+        // read-tree leaves HEAD on the cloned branch, so point it at the commit
+        run_git(
+            &["-C", dest, "update-ref", "--no-deref", "HEAD", "FETCH_HEAD"],
+            token,
+        )?;
+    }
+    Ok(())
+}
+
+/// Major and minor version of the git binary, or zeros when it's missing.
+fn git_version() -> (u32, u32) {
+    let output = match Command::new("git").arg("--version").output() {
+        Ok(o) if o.status.success() => o,
+        _ => return (0, 0),
+    };
+
+    // e.g. "git version 2.39.2 (Apple Git-143)"
+    let version = String::from_utf8_lossy(&output.stdout);
+    let mut parts = version
+        .split_whitespace()
+        .nth(2)
+        .unwrap_or_default()
+        .split('.')
+        .map(|p| p.parse::<u32>().unwrap_or(0));
+
+    (parts.next().unwrap_or(0), parts.next().unwrap_or(0))
+}
+
+fn run_git(args: &[&str], token: Option<&str>) -> AppResult<()> {
+    let mut cmd = Command::new("git");
+    cmd.args(args).env("GIT_TERMINAL_PROMPT", "0");
+
+    // pass the token through the environment so it never shows up in args
+    if let Some(t) = token {
+        let basic =
+            base64::engine::general_purpose::STANDARD.encode(format!("x-access-token:{}", t));
+        cmd.env("GIT_CONFIG_COUNT", "1")
+            .env("GIT_CONFIG_KEY_0", "http.extraHeader")
+            .env(
+                "GIT_CONFIG_VALUE_0",
+                format!("Authorization: Basic {}", basic),
+            );
+    }
+
+    let output = cmd.output()?;
+    if !output.status.success() {
+        return Err(Error::BadGit(
+            ErrorCode::GenericError,
+            String::from_utf8_lossy(&output.stderr).trim().into(),
+        ));
+    }
+
+    Ok(())
+}
+
+fn is_hex(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+/// Fetches a single ref into `dest` without history and checks it out,
+/// limited to `patterns` when any are given.
+pub fn shallow_clone(
+    url: &str,
+    dest: &str,
+    git_ref: Option<&GitRef>,
+    token: Option<&str>,
+    patterns: &[String],
+) -> AppResult<()> {
+    let repo = Repository::init(dest)?;
+    let mut remote = repo.remote("origin", url)?;
+
+    let want = {
+        let conn = remote.connect_auth(Direction::Fetch, Some(callbacks(token)), None)?;
+        let advertised: Vec<String> = conn.list()?.iter().map(|h| h.name().into()).collect();
+
+        match git_ref {
+            None => Want::Name(conn.default_branch()?.as_str().unwrap_or("HEAD").into()),
+            Some(r) => resolve_ref(r, &advertised)?,
+        }
+    };
+
+    let mut fetch_options = FetchOptions::new();
+    fetch_options
+        .remote_callbacks(callbacks(token))
+        .download_tags(AutotagOption::None);
+
+    let commit = match want {
+        Want::Name(spec) | Want::Commit(spec) => {
+            fetch_options.depth(1);
+            remote.fetch(
+                &[format!("+{}:refs/rsieve/head", spec)],
+                Some(&mut fetch_options),
+                None,
+            )?;
+            repo.find_reference("refs/rsieve/head")?.peel_to_commit()?
+        }
+        Want::Search(short) => {
+            remote.fetch(
+                &[
+                    "+refs/heads/*:refs/remotes/origin/*",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                Some(&mut fetch_options),
+                None,
+            )?;
+            repo.revparse_single(&short)?.peel_to_commit()?
+        }
+    };
+
+    let mut checkout = CheckoutBuilder::new();
+    checkout.force();
+    for p in patterns {
+        checkout.path(p.trim_start_matches('/').trim_end_matches('/'));
+    }
+    repo.checkout_tree(commit.as_object(), Some(&mut checkout))?;
+    repo.set_head_detached(commit.id())?;
+
+    Ok(())
+}
+
+fn resolve_ref(git_ref: &GitRef, advertised: &[String]) -> AppResult<Want> {
+    let candidates = match git_ref {
+        GitRef::Branch(b) => vec![format!("refs/heads/{}", b)],
+        GitRef::Rev(r) => vec![
+            format!("refs/tags/{}", r),
+            format!("refs/heads/{}", r),
+            r.to_owned(),
+        ],
+    };
+
+    if let Some(name) = candidates.into_iter().find(|c| advertised.contains(c)) {
+        return Ok(Want::Name(name));
+    }
+
+    match git_ref {
+        GitRef::Rev(r) if is_hex(r) && r.len() == 40 => Ok(Want::Commit(r.to_owned())),
+        GitRef::Rev(r) if is_hex(r) && r.len() >= 4 => Ok(Want::Search(r.to_owned())),
+        _ => Err(Error::BadGit(
+            ErrorCode::NotFound,
+            format!("ref {} not found on remote", git_ref.name()),
+        )),
+    }
+}
+
+/// Tries the ssh-agent, then the usual key files for SSH remotes, and an
+/// access token, then git's credential helper for HTTPS remotes.
+fn callbacks(token: Option<&str>) -> RemoteCallbacks<'_> {
+    let key_files: Vec<PathBuf> = match home::home_dir() {
+        Some(home) => ["id_ed25519", "id_ecdsa", "id_rsa"]
+            .iter()
+            .map(|k| home.join(".ssh").join(k))
+            .filter(|k| k.is_file())
+            .collect(),
+        None => Vec::new(),
+    };
+    let mut ssh_attempts = 0;
+    let mut token_tried = false;
+    let mut helper_tried = false;
+
+    let mut callbacks = RemoteCallbacks::new();
+    callbacks.credentials(move |url, username, allowed| {
+        if allowed.contains(CredentialType::SSH_KEY) {
+            let user = username.unwrap_or("git");
+            ssh_attempts += 1;
+            if ssh_attempts == 1 {
+                return Cred::ssh_key_from_agent(user);
+            }
+            if let Some(key) = key_files.get(ssh_attempts - 2) {
+                return Cred::ssh_key(user, None, key, None);
+            }
+        } else if allowed.contains(CredentialType::USER_PASS_PLAINTEXT) {
+            if let (Some(t), false) = (token, token_tried) {
+                token_tried = true;
+                return Cred::userpass_plaintext("x-access-token", t);
+            }
+            if !helper_tried {
+                helper_tried = true;
+                return Cred::credential_helper(&git2::Config::open_default()?, url, username);
+            }
+        } else if allowed.contains(CredentialType::DEFAULT) {
+            return Cred::default();
+        }
+
+        Err(git2::Error::from_str("no usable credentials for remote"))
+    });
+
+    callbacks
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::directories::test_dir;
 
     #[test]
     #[ignore]
@@ -25,4 +385,179 @@ mod tests {
 
         assert_eq!(default_branch, "main");
     }
+
+    fn commit_file(repo: &Repository, contents: &str) -> git2::Oid {
+        let workdir = repo.workdir().unwrap();
+        std::fs::write(workdir.join("hello.txt"), contents).unwrap();
+
+        let mut index = repo.index().unwrap();
+        index.add_path(std::path::Path::new("hello.txt")).unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let sig = git2::Signature::now("rsieve", "rsieve@example.com").unwrap();
+        let parents = match repo.head() {
+            Ok(h) => vec![h.peel_to_commit().unwrap()],
+            Err(_) => vec![],
+        };
+        let parents: Vec<&git2::Commit> = parents.iter().collect();
+
+        repo.commit(Some("HEAD"), &sig, &sig, contents, &tree, &parents)
+            .unwrap()
+    }
+
+    #[test]
+    fn it_clones_refs_natively() {
+        let base = test_dir("it_clones_refs_natively");
+        let source = Repository::init(base.join("source")).unwrap();
+        let first = commit_file(&source, "one");
+        source
+            .tag_lightweight("v1", &source.find_object(first, None).unwrap(), false)
+            .unwrap();
+        commit_file(&source, "two");
+
+        let url = format!("file://{}", base.join("source").display());
+        let read = |dir: &str| std::fs::read_to_string(base.join(dir).join("hello.txt")).unwrap();
+
+        let dest = base.join("head").display().to_string();
+        shallow_clone(&url, &dest, None, None, &[]).expect("should clone default branch");
+        assert_eq!(read("head"), "two");
+
+        let dest = base.join("tag").display().to_string();
+        shallow_clone(&url, &dest, Some(&GitRef::Rev("v1".into())), None, &[])
+            .expect("should clone tag");
+        assert_eq!(read("tag"), "one");
+
+        let short = first.to_string()[..7].to_owned();
+        let dest = base.join("short").display().to_string();
+        shallow_clone(&url, &dest, Some(&GitRef::Rev(short)), None, &[])
+            .expect("should clone short sha");
+        assert_eq!(read("short"), "one");
+
+        let dest = base.join("missing").display().to_string();
+        let res = shallow_clone(&url, &dest, Some(&GitRef::Branch("nope".into())), None, &[]);
+        assert!(res.is_err());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_reads_protected_branches() {
+        let base = test_dir("it_reads_protected_branches");
+        let repo = Repository::init(&base).unwrap();
+        let mut config = repo.config().unwrap();
+        config
+            .set_multivar("rsieve.protectedBranch", "^$", "main")
+            .unwrap();
+        config
+            .set_multivar("rsieve.protectedBranch", "^$", "release/*, hotfix")
+            .unwrap();
+
+        let dir = base.display().to_string();
+        assert_eq!(
+            protected_branches(&dir).unwrap(),
+            vec!["main", "release/*", "hotfix"]
+        );
+        assert!(origin_url(&dir).is_err());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_reads_the_repo_around_a_new_destination() {
+        let base = test_dir("it_reads_the_repo_around_a_new_destination");
+        let repo = Repository::init(&base).unwrap();
+        repo.remote("origin", "https://github.com/owner/repo.git")
+            .unwrap();
+        repo.reference_symbolic(
+            "refs/remotes/origin/HEAD",
+            "refs/remotes/origin/trunk",
+            true,
+            "",
+        )
+        .unwrap();
+        repo.config()
+            .unwrap()
+            .set_multivar("rsieve.protectedBranch", "^$", "release")
+            .unwrap();
+
+        // copying into a directory that's about to be created
+        let dest = base.join("new-dir/nested").display().to_string();
+        assert_eq!(default_branch(&dest).unwrap(), "trunk");
+        assert_eq!(protected_branches(&dest).unwrap(), vec!["release"]);
+        assert_eq!(
+            origin_url(&dest).unwrap(),
+            "https://github.com/owner/repo.git"
+        );
+        assert_eq!(
+            crate::placeholders::builtin_vars(&dest)["project_name"],
+            "nested"
+        );
+        assert!(!base.join("new-dir").exists());
+
+        std::fs::remove_dir_all(base).unwrap();
+    }
+
+    #[test]
+    fn it_translates_filters_to_sparse_patterns() {
+        assert!(sparse_patterns(&None, None).is_empty());
+        assert_eq!(
+            sparse_patterns(&None, Some(".github/*")),
+            vec!["/.github/*".to_owned(), "/rsieve.toml".to_owned()]
+        );
+        assert_eq!(
+            sparse_patterns(&Some("templates/web".into()), None),
+            vec!["/templates/web/".to_owned()]
+        );
+        assert_eq!(
+            sparse_patterns(&Some("templates".into()), Some("*.md")),
+            vec![
+                "/templates/*.md".to_owned(),
+                "/templates/rsieve.toml".to_owned()
+            ]
+        );
+    }
+
+    #[test]
+    fn it_clones_sparsely() {
+        let base = test_dir("it_clones_sparsely");
+        let source = Repository::init(base.join("source")).unwrap();
+        commit_file(&source, "one");
+        let workdir = source.workdir().unwrap();
+        fs::create_dir_all(workdir.join(".github/workflows")).unwrap();
+        fs::write(workdir.join(".github/workflows/ci.yml"), "on: push").unwrap();
+        let mut index = source.index().unwrap();
+        index
+            .add_path(std::path::Path::new(".github/workflows/ci.yml"))
+            .unwrap();
+        index.write().unwrap();
+        let second = commit_file(&source, "two");
+        commit_file(&source, "three");
+
+        let url = format!("file://{}", base.join("source").display());
+        let patterns = sparse_patterns(&None, Some(".github/*"));
+
+        let dest = base.join("git").display().to_string();
+        if sparse_clone(&url, &dest, None, None, &patterns).expect("should clone sparsely") {
+            assert!(base.join("git/.github/workflows/ci.yml").exists());
+            assert!(!base.join("git/hello.txt").exists());
+        }
+
+        let dest = base.join("pinned").display().to_string();
+        let pinned = GitRef::Rev(second.to_string());
+        if sparse_clone(&url, &dest, Some(&pinned), None, &patterns).expect("should clone a sha") {
+            assert_eq!(head_commit(&dest).unwrap(), second.to_string());
+            assert!(base.join("pinned/.github/workflows/ci.yml").exists());
+        }
+
+        let missing = format!("file://{}", base.join("missing").display());
+        let dest = base.join("failed").display().to_string();
+        assert!(!sparse_clone(&missing, &dest, None, None, &patterns).unwrap());
+        assert!(!base.join("failed").exists());
+
+        let dest = base.join("git2").display().to_string();
+        shallow_clone(&url, &dest, None, None, &patterns).expect("should clone with paths");
+        assert!(base.join("git2/.github/workflows/ci.yml").exists());
+        assert!(!base.join("git2/hello.txt").exists());
+
+        fs::remove_dir_all(base).unwrap();
+    }
 }
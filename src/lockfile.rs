@@ -0,0 +1,455 @@
+// Records where the files copied into a destination came from
+use crate::config::Config;
+use crate::directories::{self, Layout, Outcome};
+use crate::downloaders::SymlinkPolicy;
+use crate::errors;
+use crate::placeholders::Vars;
+use crate::repos::{self, GitRef, RepoMeta};
+use errors::{AppResult, Error};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Name of the lockfile, written at the root of the destination.
+pub const LOCK_FILE: &str = ".rsieve.lock";
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Lock {
+    pub source: Source,
+    #[serde(default)]
+    pub filters: Filters,
+    /// Every variable the files were rendered with, in plain text, so an
+    /// update can render them the same way.
+    #[serde(default)]
+    pub vars: Vars,
+    /// Hash of the template's version of each file rsieve manages, as last
+    /// copied or updated, by its path relative to the destination. A file
+    /// that no longer matches has been changed locally.
+    #[serde(default)]
+    pub files: BTreeMap<String, String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Source {
+    pub host: String,
+    pub repo: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subdir: Option<String>,
+    /// Requested branch. With neither this nor `ref`, the primary branch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<String>,
+    /// Requested tag, commit SHA, or branch.
+    #[serde(default, rename = "ref", skip_serializing_if = "Option::is_none")]
+    pub git_ref: Option<String>,
+    /// SHA of the commit fetched, when the forge told us.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub commit: Option<String>,
+    /// Fetched with git instead of as a tarball.
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub git: bool,
+}
+
+impl Source {
+    pub fn new(
+        repo: &RepoMeta,
+        git_ref: Option<&GitRef>,
+        commit: Option<String>,
+        git: bool,
+    ) -> Source {
+        let mut source = Source {
+            host: repo.host.clone(),
+            repo: repo.url_stem.clone(),
+            subdir: repo.subdir.clone(),
+            commit,
+            git,
+            ..Source::default()
+        };
+        source.set_git_ref(git_ref);
+
+        source
+    }
+
+    /// The repo to fetch from again.
+    pub fn repo_meta(&self, config: &Config) -> AppResult<RepoMeta> {
+        let input = format!("https://{}/{}", self.host, self.repo);
+        let mut repo = repos::parse_repo_input(&input, config)?;
+        repo.subdir = self.subdir.clone();
+
+        Ok(repo)
+    }
+
+    /// The repo and subdirectory, as `host/owner/repo/subdir`.
+    pub fn describe(&self) -> String {
+        match &self.subdir {
+            Some(sub) => format!("{}/{}/{}", self.host, self.repo, sub),
+            None => format!("{}/{}", self.host, self.repo),
+        }
+    }
+
+    /// The ref that was asked for, if any.
+    pub fn git_ref(&self) -> Option<GitRef> {
+        match (&self.branch, &self.git_ref) {
+            (_, Some(r)) => Some(GitRef::Rev(r.clone())),
+            (Some(b), None) => Some(GitRef::Branch(b.clone())),
+            (None, None) => None,
+        }
+    }
+
+    pub fn set_git_ref(&mut self, git_ref: Option<&GitRef>) {
+        let (branch, git_ref) = match git_ref {
+            Some(GitRef::Branch(b)) => (Some(b.clone()), None),
+            Some(GitRef::Rev(r)) => (None, Some(r.clone())),
+            None => (None, None),
+        };
+        self.branch = branch;
+        self.git_ref = git_ref;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct Filters {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub filter: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub include: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub exclude: Vec<String>,
+    /// Relative to the destination when it's inside it, absolute otherwise,
+    /// so it's found wherever `rsieve update` runs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ignore_file: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "is_false")]
+    pub flatten: bool,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    pub strip_components: usize,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub render_exts: Vec<String>,
+    /// Variables `$name` was filled in for, as built-ins only are when
+    /// given or declared.
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub dollar_names: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "is_preserve")]
+    pub symlinks: SymlinkPolicy,
+}
+
+fn is_false(b: &bool) -> bool {
+    !b
+}
+
+fn is_zero(n: &usize) -> bool {
+    *n == 0
+}
+
+fn is_preserve(policy: &SymlinkPolicy) -> bool {
+    *policy == SymlinkPolicy::Preserve
+}
+
+impl Filters {
+    pub fn layout(&self) -> Layout {
+        if self.flatten {
+            Layout::Flatten
+        } else {
+            Layout::Keep {
+                strip: self.strip_components,
+            }
+        }
+    }
+
+    pub fn set_layout(&mut self, layout: Layout) {
+        match layout {
+            Layout::Flatten => self.flatten = true,
+            Layout::Keep { strip } => self.strip_components = strip,
+        }
+    }
+
+    /// The ignore file, as a path from the current directory.
+    pub fn ignore_file_in(&self, destination: &str) -> Option<PathBuf> {
+        self.ignore_file
+            .as_ref()
+            .map(|p| Path::new(destination).join(p))
+    }
+
+    pub fn set_ignore_file(&mut self, destination: &str, ignore_file: Option<&Path>) {
+        self.ignore_file = ignore_file.map(|p| {
+            let path = directories::resolve(&p.display().to_string());
+            match path.strip_prefix(directories::resolve(destination)) {
+                Ok(rel_path) => rel_path.to_path_buf(),
+                Err(_) => path,
+            }
+        });
+    }
+}
+
+impl Lock {
+    pub fn from_toml(contents: &str) -> AppResult<Lock> {
+        toml::from_str(contents).map_err(|e| Error::BadLock(e.message().into()))
+    }
+
+    /// Reads the lockfile in the destination, if there is one.
+    pub fn read(destination: &str) -> AppResult<Option<Lock>> {
+        let path = Path::new(destination).join(LOCK_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        Lock::from_toml(&fs::read_to_string(path)?).map(Some)
+    }
+
+    pub fn write(&self, destination: &str) -> AppResult<PathBuf> {
+        let contents = toml::to_string_pretty(self).map_err(|e| Error::BadLock(e.to_string()))?;
+        let path = Path::new(destination).join(LOCK_FILE);
+        fs::write(&path, contents)?;
+
+        Ok(path)
+    }
+
+    /// Takes in the files and variables of the lock already in `destination`,
+    /// so copying into it again keeps what earlier copies recorded. Fails if
+    /// that lock records another source or other filters, as one lock can't
+    /// describe both and the earlier copy's files would lose their record.
+    pub fn merge_existing(&mut self, destination: &str) -> AppResult<()> {
+        let mut existing = match Lock::read(destination)? {
+            Some(lock) => lock,
+            None => return Ok(()),
+        };
+        // the `$name` variables add up like the variables themselves
+        let dollar_names = std::mem::take(&mut existing.filters.dollar_names);
+        self.filters.dollar_names.extend(dollar_names);
+        existing.filters.dollar_names = self.filters.dollar_names.clone();
+
+        let source = (&self.source.host, &self.source.repo, &self.source.subdir);
+        let locked = (
+            &existing.source.host,
+            &existing.source.repo,
+            &existing.source.subdir,
+        );
+        if source != locked || self.filters != existing.filters {
+            return Err(Error::LockedElsewhere(
+                Path::new(destination).join(LOCK_FILE).display().to_string(),
+                existing.source.describe(),
+            ));
+        }
+
+        let vars = std::mem::replace(&mut self.vars, existing.vars);
+        self.vars.extend(vars);
+        let files = std::mem::replace(&mut self.files, existing.files);
+        self.files.extend(files);
+
+        Ok(())
+    }
+
+    /// Hashes the files a copy left at their paths in the destination,
+    /// leaving out ones it skipped or wrote elsewhere.
+    pub fn record_files(
+        &mut self,
+        destination: &str,
+        files: &[(PathBuf, Outcome)],
+    ) -> AppResult<()> {
+        for (path, outcome) in files {
+            match outcome {
+                Outcome::Added
+                | Outcome::Unchanged
+                | Outcome::Overwritten
+                | Outcome::BackedUp(_) => {}
+                Outcome::Skipped | Outcome::Renamed(_) => continue,
+            }
+            // the template may have deleted what was just copied
+            match fs::symlink_metadata(path) {
+                Ok(m) if m.is_file() => {}
+                _ => continue,
+            }
+
+            let rel_path = path.strip_prefix(destination)?;
+            self.files
+                .insert(lock_path(rel_path), hash(&fs::read(path)?));
+        }
+
+        Ok(())
+    }
+}
+
+/// A path as recorded in the lockfile, with `/` separators.
+pub fn lock_path(rel_path: &Path) -> String {
+    let parts: Vec<String> = rel_path
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy().into_owned())
+        .collect();
+    parts.join("/")
+}
+
+pub fn hash(contents: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(contents))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    #[test]
+    fn it_round_trips_locks() {
+        let mut lock = Lock {
+            source: Source {
+                host: "github.com".into(),
+                repo: "owner/repo".into(),
+                git_ref: Some("v1.2.0".into()),
+                commit: Some("3be9c1a5d0e6c2f1b7a8e9d4c3b2a1f0e9d8c7b6".into()),
+                ..Source::default()
+            },
+            ..Lock::default()
+        };
+        lock.filters.filter = Some(".github/*".into());
+        lock.filters.set_layout(Layout::Keep { strip: 1 });
+        lock.filters
+            .set_ignore_file("tests", Some(Path::new("tests/test_dir/.rsieveignore")));
+        lock.filters.render_exts = vec!["yml".into()];
+        lock.filters.symlinks = SymlinkPolicy::Skip;
+        lock.filters.dollar_names.insert("project_name".into());
+        lock.vars.insert("team".into(), "payments".into());
+        lock.files
+            .insert(lock_path(Path::new("workflows/ci.yml")), hash(b"on: push"));
+
+        let contents = toml::to_string_pretty(&lock).unwrap();
+        assert!(contents.contains("ref = \"v1.2.0\""));
+        assert!(!contents.contains("branch"));
+        assert!(contents.contains("symlinks = \"skip\""));
+        assert!(contents.contains("dollar_names = [\"project_name\"]"));
+        assert_eq!(Lock::from_toml(&contents).unwrap(), lock);
+        assert_eq!(lock.filters.layout(), Layout::Keep { strip: 1 });
+        assert_eq!(
+            lock.filters.ignore_file.as_deref(),
+            Some(Path::new("test_dir/.rsieveignore"))
+        );
+        assert_eq!(
+            lock.filters.ignore_file_in("tests"),
+            Some(PathBuf::from("tests/test_dir/.rsieveignore"))
+        );
+        lock.filters
+            .set_ignore_file("tests", Some(Path::new("src/main.rs")));
+        assert!(lock
+            .filters
+            .ignore_file_in("elsewhere")
+            .unwrap()
+            .is_absolute());
+        assert_eq!(lock.source.git_ref(), Some(GitRef::Rev("v1.2.0".into())));
+        assert!(Lock::from_toml("[source]\nhost = \"github.com\"").is_err());
+    }
+
+    #[test]
+    fn it_hashes_written_files() {
+        let dest = test_dir("it_hashes_written_files");
+        fs::create_dir_all(dest.join("workflows")).unwrap();
+        fs::write(dest.join("workflows/ci.yml"), "on: push").unwrap();
+        fs::write(dest.join("README.md"), "mine").unwrap();
+
+        let mut lock = Lock::default();
+        lock.record_files(
+            &dest.display().to_string(),
+            &[
+                (dest.join("workflows/ci.yml"), Outcome::Added),
+                (dest.join("README.md"), Outcome::Skipped),
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(
+            lock.files.into_iter().collect::<Vec<_>>(),
+            vec![(
+                "workflows/ci.yml".to_string(),
+                "sha256:fff71b97a5a9494941aa5f1ec40300f7e40c4d68b3890ca7a3f27b8f6270763a"
+                    .to_string()
+            )]
+        );
+
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn it_leaves_out_files_the_template_deleted() {
+        use crate::manifest::Manifest;
+        use std::collections::BTreeSet;
+
+        let dest = test_dir("it_leaves_out_files_the_template_deleted");
+        fs::create_dir_all(&dest).unwrap();
+        fs::write(dest.join("ci.yml"), "on: push").unwrap();
+        fs::write(dest.join("old.yml"), "on: push").unwrap();
+        let destination = dest.display().to_string();
+
+        let manifest = Manifest::from_toml("[files]\ndelete = [\"old.yml\"]").unwrap();
+        let owned: BTreeSet<String> = ["ci.yml", "old.yml"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let (removed, _) = manifest
+            .delete_from(&destination, &owned, |_| Ok(false))
+            .unwrap();
+        assert_eq!(removed, vec![dest.join("old.yml")]);
+
+        let mut lock = Lock::default();
+        lock.record_files(
+            &destination,
+            &[
+                (dest.join("ci.yml"), Outcome::Added),
+                (dest.join("old.yml"), Outcome::Added),
+            ],
+        )
+        .unwrap();
+        assert_eq!(lock.files.keys().collect::<Vec<_>>(), vec!["ci.yml"]);
+
+        fs::remove_dir_all(dest).unwrap();
+    }
+
+    #[test]
+    fn it_merges_into_locks_of_the_same_copy() {
+        let dest = test_dir("it_merges_into_locks_of_the_same_copy");
+        fs::create_dir_all(&dest).unwrap();
+        let destination = dest.display().to_string();
+
+        let lock = |file: &str, filter: &str| {
+            let mut lock = Lock {
+                source: Source {
+                    host: "github.com".into(),
+                    repo: "my-org/.github".into(),
+                    ..Source::default()
+                },
+                ..Lock::default()
+            };
+            lock.filters.filter = Some(filter.into());
+            lock.filters.dollar_names.insert(file.into());
+            lock.vars.insert(file.into(), "yes".into());
+            lock.files.insert(file.into(), hash(file.as_bytes()));
+            lock
+        };
+        lock("a.yml", "workflow-templates/*")
+            .write(&destination)
+            .unwrap();
+
+        let mut again = lock("b.yml", "workflow-templates/*");
+        again.merge_existing(&destination).unwrap();
+        assert_eq!(
+            again.files.keys().collect::<Vec<_>>(),
+            vec!["a.yml", "b.yml"]
+        );
+        assert_eq!(
+            again.vars.keys().collect::<Vec<_>>(),
+            vec!["a.yml", "b.yml"]
+        );
+        assert_eq!(
+            again.filters.dollar_names.iter().collect::<Vec<_>>(),
+            vec!["a.yml", "b.yml"]
+        );
+
+        let mut other = lock("b.yml", "workflow-templates/b.yml");
+        let res = other.merge_existing(&destination);
+        assert!(
+            matches!(res, Err(Error::LockedElsewhere(_, s)) if s == "github.com/my-org/.github")
+        );
+
+        fs::remove_dir_all(dest).unwrap();
+    }
+}
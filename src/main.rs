@@ -1,17 +1,31 @@
-use console::{style, Emoji, StyledObject};
+use console::{style, user_attended, Emoji, StyledObject};
+use diffs::Change;
 use directories::*;
 use downloaders::*;
-use errors::AppResult;
+use errors::{AppResult, Error};
 use repos::*;
+use std::collections::BTreeSet;
+use std::io::{self, IsTerminal};
+use std::path::Path;
 use structopt::StructOpt;
 mod app;
+mod auth;
+mod conditions;
+mod config;
+mod diffs;
 mod directories;
 mod downloaders;
 mod errors;
+mod filters;
 mod git;
+mod lockfile;
+mod manifest;
 mod placeholders;
+mod prompts;
 mod repos;
 mod tree;
+mod updates;
+mod workflows;
 
 static LOOKING_GLASS: Emoji<'_, '_> = Emoji("🔍  ", "");
 static TRUCK: Emoji<'_, '_> = Emoji("🚚  ", "");
@@ -21,46 +35,148 @@ static MICROSCOPE: Emoji<'_, '_> = Emoji("🔬  ", "");
 #[tokio::main]
 async fn main() -> AppResult<()> {
     let app = app::App::from_args();
+    let preview = match (app.diff, app.preview) {
+        (true, _) => Some(Preview::Diff),
+        (false, true) => Some(Preview::Tree),
+        (false, false) => None,
+    };
 
-    let steps = if app.preview { 2 } else { 3 };
+    let steps = if preview.is_some() { 2 } else { 3 };
     fn step_of(x: i32, steps: i32) -> StyledObject<String> {
         style(format!("[{}/{}]", x, steps)).bold().dim()
     }
 
-    let repo_meta = parse_repo_input(&app.repo)?;
+    let config = config::load()?;
+    if let Some(app::Command::Update(update)) = &app.command {
+        return updates::update(update, &config).await;
+    }
+    let repo = app
+        .repo
+        .clone()
+        .expect("Repo is required without a subcommand");
+    let repo_meta = parse_repo_input(&repo, &config)?;
+    let from_templates = app.list_templates || app.template.is_some();
+    let interactive = !app.no_input && user_attended() && io::stdin().is_terminal();
+    let on_conflict = match app.on_conflict {
+        // a diff only shows what a prompt would be about
+        Some(OnConflict::Prompt) if !interactive && preview.is_none() => {
+            return Err(Error::NotInteractive)
+        }
+        Some(strategy) => Some(strategy),
+        None if app.merge => Some(OnConflict::Skip),
+        None => None,
+    };
+    // a diff is only worth seeing against what's already there
+    let existing_ok = on_conflict.is_some() || preview == Some(Preview::Diff);
     let destination = if app.workflows && &app.destination == "." {
-        check_distination(".github", false)?
+        check_distination(".github", existing_ok)?
+    } else if from_templates && &app.destination == "." {
+        // templates are installed one file at a time, next to existing ones
+        ".github/workflows".into()
+    } else if from_templates && !Path::new(&app.destination).ends_with(".github/workflows") {
+        format!(
+            "{}/.github/workflows",
+            app.destination.trim_end_matches('/')
+        )
+    } else if from_templates {
+        app.destination.clone()
     } else {
-        check_distination(&app.destination, false)?
+        check_distination(&app.destination, existing_ok)?
     };
+    // what a diff shows can't be copied for real without a strategy
+    let needs_strategy = preview == Some(Preview::Diff)
+        && on_conflict.is_none()
+        && !from_templates
+        && check_distination(&destination, false).is_err();
 
     let tmp_dir = prep_tmp_dir()?;
 
-    println!(
-        "{} {}Getting {}...",
-        step_of(1, steps),
-        LOOKING_GLASS,
-        app.repo
-    );
-
-    if app.git || &repo_meta.protocol == "git@" {
-        git_clone(&repo_meta.url_stem, &tmp_dir, app.branch)?;
-    } else {
-        get_tarball(&repo_meta.url_stem, &tmp_dir, app.branch).await?;
-    }
+    println!("{} {}Getting {}...", step_of(1, steps), LOOKING_GLASS, repo);
 
-    let filter = if app.workflows {
+    let git_ref = match (app.git_ref, app.branch) {
+        (Some(r), _) => Some(GitRef::Rev(r)),
+        (None, Some(b)) => Some(GitRef::Branch(b)),
+        (None, None) => repo_meta.git_ref.clone(),
+    };
+    let mut filter = if app.workflows {
         Some(".github/*".into())
+    } else if from_templates {
+        Some(format!("{}/*", workflows::TEMPLATES_DIR))
     } else {
         app.filter
     };
 
-    if !app.preview {
+    let path_filter = filters::PathFilter::new(&repo_meta.subdir, filter.as_deref())?.with_rules(
+        &app.include,
+        &app.exclude,
+        app.ignore_file.as_deref(),
+    )?;
+
+    let token = auth::token_for(&repo_meta, &config);
+    let use_git = app.git || &repo_meta.protocol == "git@";
+    let requested_ref = git_ref.clone();
+    let commit = if use_git {
+        let patterns = git::sparse_patterns(&repo_meta.subdir, filter.as_deref());
+        let commit = git_clone(
+            &repo_meta,
+            &tmp_dir,
+            git_ref,
+            token.as_deref(),
+            &patterns,
+            &path_filter,
+        )?;
+        Some(commit)
+    } else {
+        get_tarball(
+            &repo_meta,
+            &tmp_dir,
+            git_ref,
+            token.as_deref(),
+            &path_filter,
+            app.symlinks,
+        )
+        .await?
+    };
+    let source = source_dir(&tmp_dir, &repo_meta.subdir)?;
+
+    if app.list_templates {
+        for t in workflows::find_templates(Path::new(&source))? {
+            let name = t.properties.name.unwrap_or_default();
+            println!("{}  {}", style(&t.id).bold(), name);
+            if let Some(d) = t.properties.description {
+                println!("    {}", d);
+            }
+            if !t.properties.categories.is_empty() {
+                println!("    {}", style(t.properties.categories.join(", ")).dim());
+            }
+        }
+        return Ok(());
+    }
+    if let Some(id) = &app.template {
+        let template = workflows::find_template(Path::new(&source), id)?;
+        let target = Path::new(&destination).join(&template.file_name);
+        // a diff labels it as blocked instead
+        if target.exists() && on_conflict.is_none() && preview != Some(Preview::Diff) {
+            return Err(Error::FileExists(target.display().to_string()));
+        }
+        filter = Some(format!(
+            "{}/{}",
+            workflows::TEMPLATES_DIR,
+            template.file_name
+        ));
+    }
+
+    let manifest = manifest::Manifest::take(Path::new(&source))?.unwrap_or_default();
+    if let Some(f) = manifest.file_filter()? {
+        filters::prune(Path::new(&source), &f)?;
+    }
+
+    if preview.is_none() {
         println!(
             "{} {}Moving {} files to {}...",
             step_of(2, steps),
             TRUCK,
-            &app.repo,
+            &repo,
             &destination
         );
     } else {
@@ -68,22 +184,289 @@ async fn main() -> AppResult<()> {
             "{} {}These files from {} would be copied to {}...",
             step_of(2, steps),
             MICROSCOPE,
-            &app.repo,
+            &repo,
             &destination
         );
     }
 
-    move_to_destination(
-        &tmp_dir,
+    let layout = choose_layout(
+        filter.as_deref(),
+        app.flatten,
+        app.keep_paths,
+        app.strip_components,
+    );
+    // the project being scaffolded, which for workflows is the current repo
+    let project_dir = if app.workflows {
+        "."
+    } else if from_templates {
+        // the destination is always <repo>/.github/workflows
+        Path::new(&destination)
+            .ancestors()
+            .nth(2)
+            .and_then(|d| d.to_str())
+            .filter(|d| !d.is_empty())
+            .unwrap_or(".")
+    } else {
+        &destination
+    };
+    let default_branch = app.default_branch || app.template.is_some();
+    let templated = default_branch
+        || !app.vars.is_empty()
+        || !manifest.variables.is_empty()
+        || !manifest.conditions.is_empty();
+    let mut vars = placeholders::Vars::new();
+    // `$name` fills in built-ins only when they're given or declared
+    let dollar_names: Vec<String> = app
+        .vars
+        .iter()
+        .map(|(name, _)| name.clone())
+        .chain(manifest.variables.iter().map(|v| v.name.clone()))
+        .collect();
+    if templated {
+        vars = placeholders::builtin_vars(project_dir);
+        if default_branch {
+            let branch = git::default_branch(project_dir).unwrap_or_else(|_| "main".into());
+            let protected = if app.protected_branches.is_empty() {
+                git::protected_branches(project_dir).unwrap_or_default()
+            } else {
+                app.protected_branches
+            };
+            let seed = git::origin_url(project_dir)
+                .unwrap_or_else(|_| vars.get("project_name").cloned().unwrap_or_default());
+            vars.extend(placeholders::workflow_vars(&branch, &protected, &seed));
+        }
+        vars.extend(app.vars);
+        if preview.is_some() {
+            manifest.fill_defaults(&mut vars);
+        } else {
+            manifest.resolve_vars(&mut vars, interactive)?;
+        }
+    }
+
+    let switched = manifest.apply_conditions(Path::new(&source), &vars)?;
+    if preview.is_some() {
+        for s in &switched {
+            let state = match s.on {
+                Some(true) => style("on").green(),
+                Some(false) => style("off").red(),
+                None => style("off, no value").yellow(),
+            };
+            let paths: Vec<String> = s.paths.iter().map(|p| p.display().to_string()).collect();
+            println!(
+                "when {} ({}): {}",
+                style(&s.when).bold(),
+                state,
+                paths.join(", ")
+            );
+        }
+    }
+
+    let mut lock = if app.lock && preview.is_none() {
+        let mut lock = lockfile::Lock {
+            source: lockfile::Source::new(&repo_meta, requested_ref.as_ref(), commit, use_git),
+            vars: vars.clone(),
+            ..lockfile::Lock::default()
+        };
+        lock.filters.filter = filter.clone();
+        lock.filters.include = app.include.clone();
+        lock.filters.exclude = app.exclude.clone();
+        lock.filters
+            .set_ignore_file(&destination, app.ignore_file.as_deref());
+        lock.filters.set_layout(layout);
+        lock.filters.render_exts = app.render_exts.clone();
+        lock.filters.symlinks = app.symlinks;
+        lock.filters.dollar_names = dollar_names.iter().cloned().collect();
+        // checked before copying, so a clash leaves the destination as it was
+        lock.merge_existing(&destination)?;
+        Some(lock)
+    } else {
+        None
+    };
+
+    let placeholders = if templated && preview != Some(Preview::Tree) {
+        let mut placeholders =
+            placeholders::Placeholders::new(vars).with_dollar_names(dollar_names);
+        if let Some(f) = manifest.render_filter()? {
+            placeholders = placeholders.with_files(Path::new(&source), f);
+        }
+        let extensions = if app.render_exts.is_empty() {
+            &manifest.files.extensions
+        } else {
+            &app.render_exts
+        };
+        if !extensions.is_empty() {
+            placeholders = placeholders.with_extensions(extensions);
+        }
+        Some(placeholders)
+    } else {
+        None
+    };
+
+    // paths rsieve wrote, which the template's deletes may remove unasked
+    let mut owned: BTreeSet<String> = lockfile::Lock::read(&destination)
+        .ok()
+        .flatten()
+        .map(|l| l.files.into_keys().collect())
+        .unwrap_or_default();
+
+    let report = move_to_destination(
+        &source,
         &destination,
         filter,
-        app.preview,
-        app.default_branch,
+        preview,
+        placeholders.as_ref(),
+        layout,
+        on_conflict,
     )?;
 
-    if !app.preview {
+    for (name, paths) in &report.unresolved {
+        let paths: Vec<String> = paths.iter().map(|p| p.display().to_string()).collect();
+        println!(
+            "{} {{{{{}}}}} left unresolved in {}",
+            style("warning:").yellow().bold(),
+            name,
+            paths.join(", ")
+        );
+    }
+
+    // a plain copy adds every file, so only merges list them
+    let merged = if on_conflict.is_some() {
+        &report.files[..]
+    } else {
+        &[]
+    };
+    for (path, outcome) in merged {
+        let (label, moved) = match outcome {
+            Outcome::Added => (style("added").green(), None),
+            Outcome::Unchanged => (style("unchanged").dim(), None),
+            Outcome::Skipped => (style("skipped").yellow(), None),
+            Outcome::Overwritten => (style("overwritten").red(), None),
+            Outcome::BackedUp(backup) => (style("backed up").cyan(), Some(backup)),
+            Outcome::Renamed(renamed) => (style("renamed").cyan(), Some(renamed)),
+        };
+        match moved {
+            Some(to) => println!("  {:>11} {} -> {}", label, path.display(), to.display()),
+            None => println!("  {:>11} {}", label, path.display()),
+        }
+    }
+    let skipped = report
+        .files
+        .iter()
+        .filter(|(_, o)| *o == Outcome::Skipped)
+        .count();
+    if skipped > 0 {
+        println!(
+            "{} {} existing file(s) differ and were kept as they are",
+            style("warning:").yellow().bold(),
+            skipped
+        );
+    }
+
+    for change in &report.changes {
+        let label = match change.change {
+            Change::New => style("new").green(),
+            Change::Modified => style("modified").yellow(),
+            Change::Unchanged => style("unchanged").dim(),
+            Change::Conflict => style("conflict").red(),
+            Change::BackedUp => style("backed up").cyan(),
+            Change::Blocked => style("blocked").red(),
+        };
+        println!("  {:>9} {}", label, change.path.display());
+        if let Some(diff) = &change.diff {
+            print_diff(diff);
+        }
+    }
+    if needs_strategy {
+        println!(
+            "{} {} isn't empty, so copying needs --merge or --on-conflict",
+            style("warning:").yellow().bold(),
+            destination
+        );
+    }
+
+    let written = report
+        .files
+        .iter()
+        .filter(|(_, o)| {
+            matches!(
+                o,
+                Outcome::Added | Outcome::Overwritten | Outcome::BackedUp(_)
+            )
+        })
+        .map(|(p, _)| p)
+        .chain(
+            report
+                .changes
+                .iter()
+                .filter(|c| matches!(c.change, Change::New | Change::Modified | Change::BackedUp))
+                .map(|c| &c.path),
+        );
+    for path in written {
+        if let Ok(rel_path) = path.strip_prefix(&destination) {
+            owned.insert(lockfile::lock_path(rel_path));
+        }
+    }
+
+    match preview {
+        None => {
+            let (removed, kept) = manifest.delete_from(&destination, &owned, |path| {
+                if !interactive {
+                    return Ok(false);
+                }
+                prompts::confirm_delete(path, &mut io::stdin().lock(), &mut io::stderr())
+            })?;
+            for path in removed {
+                println!("Removed {}", path.display());
+            }
+            for path in kept {
+                println!(
+                    "{} kept {}, which the template deletes but rsieve didn't write",
+                    style("warning:").yellow().bold(),
+                    path.display()
+                );
+            }
+        }
+        Some(Preview::Diff) => {
+            for deletion in manifest.to_delete(&destination, &owned)? {
+                let label = if deletion.owned {
+                    style("deleted").red()
+                } else {
+                    style("asks").yellow()
+                };
+                println!("  {:>9} {}", label, deletion.path.display());
+            }
+        }
+        Some(Preview::Tree) => {}
+    }
+
+    if let Some(lock) = &mut lock {
+        lock.record_files(&destination, &report.files)?;
+        println!(
+            "Recorded where files came from in {}",
+            lock.write(&destination)?.display()
+        );
+    }
+
+    if preview.is_none() {
         println!("{} {}Done!", step_of(3, steps), SPARKLE);
     }
 
     Ok(())
 }
+
+fn print_diff(diff: &str) {
+    for line in diff.lines() {
+        let line = if line.starts_with("+++") || line.starts_with("---") {
+            style(line).bold()
+        } else if line.starts_with('+') {
+            style(line).green()
+        } else if line.starts_with('-') {
+            style(line).red()
+        } else if line.starts_with("@@") {
+            style(line).cyan()
+        } else {
+            style(line)
+        };
+        println!("    {}", line);
+    }
+}
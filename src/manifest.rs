@@ -0,0 +1,504 @@
+// Reads the rsieve.toml a template ships to describe itself
+use crate::conditions::Condition;
+use crate::errors;
+use crate::filters;
+use crate::lockfile;
+use crate::placeholders;
+use crate::prompts;
+use errors::{AppResult, Error};
+use filters::PathFilter;
+use placeholders::{Placeholders, Vars};
+use regex::Regex;
+use serde::Deserialize;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+/// Name of the manifest, looked for at the root of the template.
+pub const MANIFEST_FILE: &str = "rsieve.toml";
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    #[serde(default)]
+    pub variables: Vec<Variable>,
+    #[serde(default)]
+    pub files: Files,
+    #[serde(default)]
+    pub conditions: Vec<ConditionalPaths>,
+}
+
+/// Paths only copied when a condition holds.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct ConditionalPaths {
+    pub when: Condition,
+    /// Gitignore-style patterns relative to the template root.
+    pub paths: Vec<String>,
+}
+
+/// What a condition did to the template.
+pub struct Switched {
+    pub when: Condition,
+    /// Whether the condition held, or None if its variable had no value.
+    pub on: Option<bool>,
+    pub paths: Vec<PathBuf>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Variable {
+    pub name: String,
+    pub description: Option<String>,
+    /// May use earlier variables, as in `"{{project_name}}-svc"`.
+    pub default: Option<String>,
+    /// Regex the whole value has to match.
+    pub pattern: Option<String>,
+    /// Values to pick from. Any value goes when empty.
+    #[serde(default)]
+    pub choices: Vec<String>,
+}
+
+impl Variable {
+    /// Checks a value against the variable's choices and pattern,
+    /// explaining what's wrong with it if it doesn't fit.
+    pub fn validate(&self, value: &str) -> Result<(), String> {
+        if !self.choices.is_empty() && !self.choices.iter().any(|c| c == value) {
+            return Err(format!("must be one of {}", self.choices.join(", ")));
+        }
+        if let Some(p) = &self.pattern {
+            if !anchored(p).map(|re| re.is_match(value)).unwrap_or(false) {
+                return Err(format!("must match {}", p));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn anchored(pattern: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&format!("^(?:{})$", pattern))
+}
+
+/// Gitignore-style patterns relative to the template root, except `delete`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Files {
+    /// Only paths matching one of these are copied.
+    #[serde(default)]
+    pub include: Vec<String>,
+    /// Paths that aren't copied.
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Only these files have placeholders rendered. Defaults to all.
+    #[serde(default)]
+    pub render: Vec<String>,
+    /// Files copied as they are, without rendering.
+    #[serde(default)]
+    pub verbatim: Vec<String>,
+    /// Extensions of the files to render, as `yml` or `.yml`. Defaults to
+    /// every text file.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// Paths, relative to the destination, removed after copying.
+    #[serde(default)]
+    pub delete: Vec<String>,
+}
+
+impl Manifest {
+    pub fn from_toml(contents: &str) -> AppResult<Manifest> {
+        let manifest: Manifest =
+            toml::from_str(contents).map_err(|e| Error::BadManifest(e.message().into()))?;
+
+        for var in &manifest.variables {
+            if let Some(Err(e)) = var.pattern.as_deref().map(anchored) {
+                return Err(Error::BadManifest(format!("{}: {}", var.name, e)));
+            }
+        }
+        // no paths would otherwise match the whole template
+        if let Some(c) = manifest.conditions.iter().find(|c| c.paths.is_empty()) {
+            return Err(Error::BadManifest(format!(
+                "The condition {} lists no paths.",
+                c.when
+            )));
+        }
+
+        Ok(manifest)
+    }
+
+    /// Reads the manifest at the root of the template, if there is one, and
+    /// takes it out so it isn't copied along with the template.
+    pub fn take(source: &Path) -> AppResult<Option<Manifest>> {
+        let path = source.join(MANIFEST_FILE);
+        if !path.is_file() {
+            return Ok(None);
+        }
+
+        let manifest = Manifest::from_toml(&fs::read_to_string(&path)?)?;
+        fs::remove_file(path)?;
+
+        Ok(Some(manifest))
+    }
+
+    /// Fills in declared variables that weren't given, prompting for them
+    /// when interactive and otherwise taking their defaults.
+    pub fn resolve_vars(&self, vars: &mut Vars, interactive: bool) -> AppResult<()> {
+        for var in &self.variables {
+            if let Some(value) = vars.get(&var.name) {
+                var.validate(value)
+                    .map_err(|msg| Error::BadVariable(var.name.clone(), msg))?;
+                continue;
+            }
+
+            let default = var
+                .default
+                .as_ref()
+                .map(|d| Placeholders::new(vars.clone()).render(d).0);
+            let value = match (interactive, default) {
+                (true, default) => prompts::ask(
+                    var,
+                    default.as_deref(),
+                    &mut io::stdin().lock(),
+                    &mut io::stderr(),
+                )?,
+                (false, Some(d)) => {
+                    var.validate(&d)
+                        .map_err(|msg| Error::BadVariable(var.name.clone(), msg))?;
+                    d
+                }
+                (false, None) => {
+                    return Err(Error::MissingVariable(
+                        var.name.clone(),
+                        var.description.clone(),
+                    ))
+                }
+            };
+            vars.insert(var.name.clone(), value);
+        }
+
+        Ok(())
+    }
+
+    /// Fills in declared variables that weren't given with their defaults,
+    /// leaving out any without one.
+    pub fn fill_defaults(&self, vars: &mut Vars) {
+        for var in &self.variables {
+            if let (false, Some(d)) = (vars.contains_key(&var.name), &var.default) {
+                let (value, _) = Placeholders::new(vars.clone()).render(d);
+                vars.insert(var.name.clone(), value);
+            }
+        }
+    }
+
+    /// Removes the paths of conditions that don't hold from the template
+    /// under `source`, reporting what each condition covered.
+    pub fn apply_conditions(&self, source: &Path, vars: &Vars) -> AppResult<Vec<Switched>> {
+        let mut switched = Vec::new();
+
+        for condition in &self.conditions {
+            let filter = match rules_filter(&condition.paths, &[])? {
+                Some(f) => f,
+                None => continue,
+            };
+            let paths = filters::find(source, &filter)?;
+            let on = condition.when.eval(vars);
+
+            if on != Some(true) {
+                for rel_path in &paths {
+                    let path = source.join(rel_path);
+                    if fs::symlink_metadata(&path)?.is_dir() {
+                        fs::remove_dir_all(path)?;
+                    } else {
+                        fs::remove_file(path)?;
+                    }
+                }
+            }
+
+            switched.push(Switched {
+                when: condition.when.clone(),
+                on,
+                paths,
+            });
+        }
+
+        Ok(switched)
+    }
+
+    /// Filter for the manifest's include and exclude rules, if it has any.
+    pub fn file_filter(&self) -> AppResult<Option<PathFilter>> {
+        rules_filter(&self.files.include, &self.files.exclude)
+    }
+
+    /// Filter for which files get rendered, if the manifest narrows it.
+    pub fn render_filter(&self) -> AppResult<Option<PathFilter>> {
+        rules_filter(&self.files.render, &self.files.verbatim)
+    }
+
+    /// The paths listed under `delete` that are in the destination. `owned`
+    /// holds the lockfile paths of the files rsieve wrote there.
+    pub fn to_delete(
+        &self,
+        destination: &str,
+        owned: &BTreeSet<String>,
+    ) -> AppResult<Vec<Deletion>> {
+        let mut deletions = Vec::new();
+
+        for rel_path in &self.files.delete {
+            let rel_path = Path::new(rel_path);
+            if !rel_path
+                .components()
+                .all(|c| matches!(c, Component::Normal(_)))
+            {
+                return Err(Error::BadManifest(format!(
+                    "Can't delete {} from outside the destination.",
+                    rel_path.display()
+                )));
+            }
+
+            let destination = Path::new(destination);
+            for parent in rel_path.ancestors().skip(1) {
+                let linked = fs::symlink_metadata(destination.join(parent))
+                    .map(|m| m.file_type().is_symlink())
+                    .unwrap_or(false);
+                if linked {
+                    return Err(Error::BadManifest(format!(
+                        "Can't delete {} through the symlink {}.",
+                        rel_path.display(),
+                        parent.display()
+                    )));
+                }
+            }
+
+            let path = destination.join(rel_path);
+            if fs::symlink_metadata(&path).is_ok() {
+                deletions.push(Deletion {
+                    owned: all_owned(&path, rel_path, owned)?,
+                    path,
+                });
+            }
+        }
+
+        Ok(deletions)
+    }
+
+    /// Removes the paths listed under `delete` from the destination. Ones
+    /// rsieve didn't write are only removed if `confirm` agrees. Returns
+    /// the paths removed and the ones kept.
+    pub fn delete_from(
+        &self,
+        destination: &str,
+        owned: &BTreeSet<String>,
+        mut confirm: impl FnMut(&Path) -> AppResult<bool>,
+    ) -> AppResult<(Vec<PathBuf>, Vec<PathBuf>)> {
+        let (mut removed, mut kept) = (Vec::new(), Vec::new());
+
+        for deletion in self.to_delete(destination, owned)? {
+            if !deletion.owned && !confirm(&deletion.path)? {
+                kept.push(deletion.path);
+                continue;
+            }
+
+            if fs::symlink_metadata(&deletion.path)?.is_dir() {
+                fs::remove_dir_all(&deletion.path)?;
+            } else {
+                fs::remove_file(&deletion.path)?;
+            }
+            removed.push(deletion.path);
+        }
+
+        Ok((removed, kept))
+    }
+}
+
+/// A path listed under `delete` that's in the destination.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Deletion {
+    pub path: PathBuf,
+    /// Whether rsieve wrote it, or everything in it. Other paths are only
+    /// removed when confirmed.
+    pub owned: bool,
+}
+
+fn all_owned(path: &Path, rel_path: &Path, owned: &BTreeSet<String>) -> AppResult<bool> {
+    if !fs::symlink_metadata(path)?.is_dir() {
+        return Ok(owned.contains(&lockfile::lock_path(rel_path)));
+    }
+
+    let mut any = false;
+    for entry in fs::read_dir(path)? {
+        let name = entry?.file_name();
+        if !all_owned(&path.join(&name), &rel_path.join(&name), owned)? {
+            return Ok(false);
+        }
+        any = true;
+    }
+
+    Ok(any)
+}
+
+fn rules_filter(includes: &[String], excludes: &[String]) -> AppResult<Option<PathFilter>> {
+    if includes.is_empty() && excludes.is_empty() {
+        return Ok(None);
+    }
+
+    let filter = PathFilter::new(&None, None)?
+        .with_rules(includes, excludes, None)
+        .map_err(|e| Error::BadManifest(e.to_string()))?;
+
+    Ok(Some(filter))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    fn sample() -> Manifest {
+        Manifest::from_toml(
+            r#"
+            [[variables]]
+            name = "service"
+            description = "Service name"
+            default = "{{project_name}}-svc"
+
+            [[variables]]
+            name = "team"
+            pattern = "[a-z]+"
+
+            [[variables]]
+            name = "region"
+            choices = ["us", "eu"]
+            default = "us"
+
+            [files]
+            exclude = ["tests/"]
+            verbatim = ["*.png"]
+            delete = ["old.yml"]
+
+            [[conditions]]
+            when = "use_docker"
+            paths = ["docker/", "Dockerfile"]
+            "#,
+        )
+        .expect("manifest should parse")
+    }
+
+    #[test]
+    fn it_resolves_defaults_in_order() {
+        let manifest = sample();
+        let mut vars = Vars::new();
+        vars.insert("project_name".into(), "billing".into());
+        vars.insert("team".into(), "payments".into());
+
+        manifest.resolve_vars(&mut vars, false).unwrap();
+        assert_eq!(vars["service"], "billing-svc");
+        assert_eq!(vars["region"], "us");
+
+        vars.remove("team");
+        assert!(matches!(
+            manifest.resolve_vars(&mut vars, false),
+            Err(Error::MissingVariable(..))
+        ));
+    }
+
+    #[test]
+    fn it_validates_given_values() {
+        let manifest = sample();
+        let mut vars = Vars::new();
+        vars.insert("team".into(), "Payments".into());
+
+        assert!(matches!(
+            manifest.resolve_vars(&mut vars, false),
+            Err(Error::BadVariable(..))
+        ));
+
+        let region = &manifest.variables[2];
+        assert!(region.validate("eu").is_ok());
+        assert!(region.validate("ap").is_err());
+    }
+
+    #[test]
+    fn it_builds_file_filters() {
+        let manifest = sample();
+
+        let files = manifest.file_filter().unwrap().unwrap();
+        assert!(files.matches(Path::new("src/main.rs"), false));
+        assert!(!files.matches(Path::new("tests/main.rs"), false));
+
+        let render = manifest.render_filter().unwrap().unwrap();
+        assert!(!render.matches(Path::new("logo.png"), false));
+        assert!(Manifest::default().render_filter().unwrap().is_none());
+    }
+
+    #[test]
+    fn it_removes_paths_of_conditions_that_dont_hold() {
+        let root = test_dir("it_removes_conditional_paths");
+        for file in ["docker/compose.yml", "Dockerfile", "src/main.rs"] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+
+        let mut vars = Vars::new();
+        vars.insert("use_docker".into(), "no".into());
+        let switched = sample().apply_conditions(&root, &vars).unwrap();
+
+        assert_eq!(switched[0].on, Some(false));
+        assert_eq!(
+            switched[0].paths,
+            vec![PathBuf::from("Dockerfile"), PathBuf::from("docker")]
+        );
+        assert!(!root.join("docker").exists());
+        assert!(!root.join("Dockerfile").exists());
+        assert!(root.join("src/main.rs").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn it_rejects_unknown_keys_and_unsafe_deletes() {
+        assert!(Manifest::from_toml("[files]\nrendr = [\"*.md\"]").is_err());
+        assert!(Manifest::from_toml("[[variables]]\nname = \"x\"\npattern = \"(\"").is_err());
+        assert!(Manifest::from_toml("[[conditions]]\nwhen = \"a b\"\npaths = [\"x\"]").is_err());
+        assert!(Manifest::from_toml("[[conditions]]\nwhen = \"a\"\npaths = []").is_err());
+
+        let manifest = Manifest::from_toml("[files]\ndelete = [\"../x\"]").unwrap();
+        assert!(manifest
+            .delete_from("dest", &BTreeSet::new(), |_| Ok(true))
+            .is_err());
+    }
+
+    #[test]
+    fn it_only_deletes_owned_paths_without_asking() {
+        let root = test_dir("it_only_deletes_owned_paths");
+        for file in ["old.yml", "src/main.rs", "docs/old.md", "real/a.yml"] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+        std::os::unix::fs::symlink(root.join("real"), root.join("linked")).unwrap();
+
+        let manifest = Manifest::from_toml(
+            "[files]\ndelete = [\"old.yml\", \"src\", \"docs\", \"missing.yml\"]",
+        )
+        .unwrap();
+        let owned: BTreeSet<String> = ["old.yml", "docs/old.md"]
+            .iter()
+            .map(|p| p.to_string())
+            .collect();
+        let dest = root.display().to_string();
+
+        let (removed, kept) = manifest.delete_from(&dest, &owned, |_| Ok(false)).unwrap();
+        assert_eq!(removed, vec![root.join("old.yml"), root.join("docs")]);
+        assert_eq!(kept, vec![root.join("src")]);
+        assert!(root.join("src/main.rs").exists());
+
+        let (removed, _) = manifest.delete_from(&dest, &owned, |_| Ok(true)).unwrap();
+        assert_eq!(removed, vec![root.join("src")]);
+
+        let manifest = Manifest::from_toml("[files]\ndelete = [\"linked/a.yml\"]").unwrap();
+        assert!(manifest.delete_from(&dest, &owned, |_| Ok(true)).is_err());
+        assert!(root.join("real/a.yml").exists());
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
@@ -1,32 +1,832 @@
+// Substitutes template variables in copied files
+use crate::conditions::Condition;
+use crate::directories;
 use crate::errors;
-use errors::AppResult;
-use std::fs::{read_to_string, OpenOptions};
-use std::io::prelude::*;
+use crate::filters::PathFilter;
+use errors::{AppResult, Error};
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::path::{Component, Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
 
-pub fn update_placeholder_branch(file_name: &str, default_branch: &str) -> AppResult<()> {
-    let contents = read_to_string(file_name)?;
-    let new = contents.replace("$default-branch", default_branch);
+pub type Vars = BTreeMap<String, String>;
 
-    let mut file = OpenOptions::new()
-        .write(true)
-        .truncate(true)
-        .open(file_name)?;
-    file.write_all(new.as_bytes())?;
+/// Placeholder names left in the copied files, with where each was found.
+pub type Unresolved = BTreeMap<String, BTreeSet<PathBuf>>;
 
-    Ok(())
+/// Replaces `{{name}}` with the variable's value, and `$name` for names that
+/// are defined, such as `$default-branch`, other than the built-ins. GitHub's
+/// `${{ ... }}` expressions are left alone, and a leading backslash
+/// (`\{{name}}`, `\$name`) keeps a placeholder as written.
+pub struct Placeholders {
+    vars: Vars,
+    /// Built-in names that `$name` fills in too, as they were given or
+    /// declared rather than built in.
+    dollar_names: BTreeSet<String>,
+    /// Template root and which files below it to render, when not all.
+    files: Option<(PathBuf, PathFilter)>,
+    /// Lowercase extensions of the files to render, when not all.
+    extensions: Option<Vec<String>>,
+}
+
+impl Placeholders {
+    pub fn new(vars: Vars) -> Placeholders {
+        Placeholders {
+            vars,
+            dollar_names: BTreeSet::new(),
+            files: None,
+            extensions: None,
+        }
+    }
+
+    /// Fills in `$name` for these names even when they're built-ins.
+    pub fn with_dollar_names(mut self, names: impl IntoIterator<Item = String>) -> Placeholders {
+        self.dollar_names.extend(names);
+        self
+    }
+
+    /// Renders only the files under `root` that the filter matches.
+    pub fn with_files(mut self, root: &Path, filter: PathFilter) -> Placeholders {
+        self.files = Some((root.to_path_buf(), filter));
+        self
+    }
+
+    /// Renders only files with one of these extensions, as `yml` or `.yml`.
+    pub fn with_extensions(mut self, extensions: &[String]) -> Placeholders {
+        self.extensions = Some(
+            extensions
+                .iter()
+                .map(|e| e.trim_start_matches('.').to_lowercase())
+                .collect(),
+        );
+        self
+    }
+
+    /// Renders text, returning it with the `{{name}}` placeholders that had
+    /// no value. `{{#if condition}}`, `{{else}}` and `{{/if}}` keep or drop
+    /// what's between them, and drop their own line when alone on it. A
+    /// block whose variable has no value, like an `{{#if}}` that's never
+    /// closed, is left as written and reported too.
+    pub fn render(&self, text: &str) -> (String, BTreeSet<String>) {
+        let mut unclosed = BTreeSet::new();
+
+        loop {
+            match self.render_blocks(text, &unclosed) {
+                Ok(rendered) => return rendered,
+                Err(start) => unclosed.insert(start),
+            };
+        }
+    }
+
+    /// Renders text, taking the tags at the `unclosed` offsets as plain text.
+    /// Fails with the offset of the first `{{#if}}` left open.
+    fn render_blocks(
+        &self,
+        text: &str,
+        unclosed: &BTreeSet<usize>,
+    ) -> Result<(String, BTreeSet<String>), usize> {
+        let mut out = String::with_capacity(text.len());
+        let mut unresolved = BTreeSet::new();
+        let mut blocks: Vec<Block> = Vec::new();
+        let mut on = true;
+        let mut rest = text;
+
+        while let Some(i) = rest.find(['{', '$', '\\']) {
+            push(&mut out, on, &rest[..i]);
+            let tail = &rest[i..];
+
+            if let Some(after) = tail.strip_prefix("\\{{") {
+                push(&mut out, on, "{{");
+                rest = after;
+            } else if let Some(len) = tail.strip_prefix("\\$").and_then(|a| self.dollar_var(a)) {
+                push(&mut out, on, &tail[1..len + 2]);
+                rest = &tail[len + 2..];
+            } else if let Some(after) = tail.strip_prefix('\\') {
+                push(&mut out, on, "\\");
+                rest = after;
+            } else if let Some(after) = tail.strip_prefix("${{") {
+                push(&mut out, on, "${{");
+                rest = after;
+            } else if let Some(after) = tail.strip_prefix("{{") {
+                let start = text.len() - tail.len();
+                let tag = after.find("}}").map(|end| (end, after[..end].trim()));
+                let was_on = on;
+                let block = match tag {
+                    Some((_, tag)) if !unclosed.contains(&start) => {
+                        self.block_tag(tag, start, &mut blocks, &mut unresolved, on)
+                    }
+                    _ => None,
+                };
+
+                match (tag, block) {
+                    (Some((_, tag)), _) if unclosed.contains(&start) => {
+                        if on {
+                            unresolved.insert(tag.to_owned());
+                        }
+                        push(&mut out, on, "{{");
+                        rest = after;
+                    }
+                    (Some((end, _)), Some(Tag::Kept)) => {
+                        push(&mut out, on, &tail[..end + 4]);
+                        rest = &after[end + 2..];
+                    }
+                    (Some((end, _)), Some(Tag::Applied)) => {
+                        on = blocks.iter().all(|b| b.active);
+                        rest = &after[end + 2..];
+
+                        // a tag alone on its line takes the line with it
+                        let line_start = text[..text.len() - tail.len()]
+                            .rfind('\n')
+                            .map(|n| n + 1)
+                            .unwrap_or(0);
+                        let indent = &text[line_start..text.len() - tail.len()];
+                        let trailing = rest.trim_start_matches([' ', '\t']);
+                        let line_end = ["\r\n", "\n", ""].iter().find(|nl| {
+                            trailing.starts_with(**nl) && (!nl.is_empty() || trailing.is_empty())
+                        });
+                        if let (true, Some(nl)) =
+                            (indent.trim_start_matches([' ', '\t']).is_empty(), line_end)
+                        {
+                            if was_on && out.ends_with(indent) {
+                                out.truncate(out.len() - indent.len());
+                            }
+                            rest = &trailing[nl.len()..];
+                        }
+                    }
+                    (Some((end, name)), None) if is_name(name) => {
+                        match self.vars.get(name) {
+                            Some(value) => push(&mut out, on, value),
+                            None => {
+                                if on {
+                                    unresolved.insert(name.to_owned());
+                                }
+                                push(&mut out, on, &tail[..end + 4]);
+                            }
+                        }
+                        rest = &after[end + 2..];
+                        continue;
+                    }
+                    _ => {
+                        push(&mut out, on, "{{");
+                        rest = after;
+                    }
+                }
+            } else if let Some(after) = tail.strip_prefix('$') {
+                match self.dollar_var(after) {
+                    Some(len) => {
+                        push(&mut out, on, &self.vars[&after[..len]]);
+                        rest = &after[len..];
+                    }
+                    None => {
+                        push(&mut out, on, "$");
+                        rest = after;
+                    }
+                }
+            } else {
+                push(&mut out, on, "{");
+                rest = &tail[1..];
+            }
+        }
+        push(&mut out, on, rest);
+
+        match blocks.first() {
+            Some(block) => Err(block.start),
+            None => Ok((out, unresolved)),
+        }
+    }
+
+    /// Opens, flips or closes a conditional block, returning None when the
+    /// tag isn't one or doesn't fit, so it's read as any other tag.
+    fn block_tag(
+        &self,
+        tag: &str,
+        start: usize,
+        blocks: &mut Vec<Block>,
+        unresolved: &mut BTreeSet<String>,
+        on: bool,
+    ) -> Option<Tag> {
+        if let Some(expr) = tag.strip_prefix("#if ") {
+            let condition = expr.parse::<Condition>().ok()?;
+            let active = condition.eval(&self.vars);
+            if active.is_none() && on {
+                unresolved.insert(condition.name().to_owned());
+            }
+            blocks.push(Block {
+                active: active.unwrap_or(true),
+                in_else: false,
+                kept: active.is_none(),
+                start,
+            });
+            return Some(Block::tag(active.is_none()));
+        }
+
+        match (tag, blocks.last_mut()) {
+            ("else", Some(block)) if !block.in_else => {
+                if !block.kept {
+                    block.active = !block.active;
+                }
+                block.in_else = true;
+                Some(Block::tag(block.kept))
+            }
+            ("/if", Some(_)) => blocks.pop().map(|b| Block::tag(b.kept)),
+            _ => None,
+        }
+    }
+
+    /// Length of the longest defined variable name starting `text`, if it
+    /// isn't just the front of a longer word. Built-ins such as `$year` are
+    /// more likely a shell variable in a copied script, so they only count
+    /// when given or declared.
+    fn dollar_var(&self, text: &str) -> Option<usize> {
+        self.vars
+            .keys()
+            .filter(|k| !BUILTIN_NAMES.contains(&k.as_str()) || self.dollar_names.contains(*k))
+            .filter(|k| text.starts_with(k.as_str()))
+            .filter(|k| {
+                !text[k.len()..].starts_with(|c: char| c.is_ascii_alphanumeric() || c == '_')
+            })
+            .map(|k| k.len())
+            .max()
+    }
+
+    /// Renders placeholders in each component of a path.
+    pub fn render_name(&self, path: &Path) -> AppResult<(PathBuf, BTreeSet<String>)> {
+        let mut rendered = PathBuf::new();
+        let mut unresolved = BTreeSet::new();
+
+        for component in path.components() {
+            match component {
+                Component::Normal(name) => {
+                    let (name, names) = self.render_component(name)?;
+                    rendered.push(name);
+                    unresolved.extend(names);
+                }
+                other => rendered.push(other),
+            }
+        }
+
+        Ok((rendered, unresolved))
+    }
+
+    /// Renders a single file or directory name, failing if the result would
+    /// be anything but one, such as `..` or a path elsewhere.
+    fn render_component(&self, name: &OsStr) -> AppResult<(OsString, BTreeSet<String>)> {
+        let n = match name.to_str() {
+            Some(n) => n,
+            None => return Ok((name.to_owned(), BTreeSet::new())),
+        };
+
+        let (rendered, names) = self.render(n);
+        if rendered != n && !is_plain_name(&rendered) {
+            return Err(Error::BadRenderedName(n.into(), rendered));
+        }
+
+        Ok((rendered.into(), names))
+    }
+
+    /// Renders a file in place, or every file and name below a directory.
+    /// `shown_as` is the path used when reporting unresolved placeholders.
+    pub fn render_path(
+        &self,
+        path: &Path,
+        shown_as: &Path,
+        unresolved: &mut Unresolved,
+    ) -> AppResult<()> {
+        self.render_entry(path, path, shown_as, unresolved)
+    }
+
+    /// `template_path` is where the entry was before any renaming, which is
+    /// what the manifest's render rules are written against.
+    fn render_entry(
+        &self,
+        path: &Path,
+        template_path: &Path,
+        shown_as: &Path,
+        unresolved: &mut Unresolved,
+    ) -> AppResult<()> {
+        let meta = fs::symlink_metadata(path)?;
+
+        if meta.is_dir() {
+            // rendered name -> name in the template, for reporting collisions
+            let mut renamed: HashMap<OsString, OsString> = HashMap::new();
+
+            for entry in fs::read_dir(path)?.collect::<Result<Vec<_>, _>>()? {
+                let name = entry.file_name();
+                let (new_name, names) = self.render_component(&name)?;
+                record(unresolved, names, &shown_as.join(&new_name));
+
+                let mut child = path.join(&name);
+                if new_name != name {
+                    let target = path.join(&new_name);
+                    if fs::symlink_metadata(&target).is_ok() {
+                        let earlier = renamed.get(&new_name).unwrap_or(&new_name);
+                        return Err(Error::PathCollision(
+                            shown_as.join(&new_name).display().to_string(),
+                            shown_as.join(earlier).display().to_string(),
+                            shown_as.join(&name).display().to_string(),
+                        ));
+                    }
+                    fs::rename(&child, &target)?;
+                    renamed.insert(new_name.clone(), name.clone());
+                    child = target;
+                }
+
+                self.render_entry(
+                    &child,
+                    &template_path.join(&name),
+                    &shown_as.join(&new_name),
+                    unresolved,
+                )?;
+            }
+            return Ok(());
+        }
+
+        // links are left pointing at whatever they pointed at
+        if !meta.is_file() {
+            return Ok(());
+        }
+
+        if let Some((root, filter)) = &self.files {
+            let rel_path = template_path.strip_prefix(root)?;
+            if !filter.matches(rel_path, false) {
+                return Ok(());
+            }
+        }
+
+        if let Some(extensions) = &self.extensions {
+            let extension = template_path
+                .extension()
+                .map(|e| e.to_string_lossy().to_lowercase())
+                .unwrap_or_default();
+            if !extensions.contains(&extension) {
+                return Ok(());
+            }
+        }
+
+        // binary files are copied untouched
+        let bytes = fs::read(path)?;
+        let (encoding, contents) = match Encoding::decode(&bytes) {
+            Some(decoded) => decoded,
+            None => return Ok(()),
+        };
+
+        let (new, names) = self.render(&contents);
+        if new != contents {
+            fs::write(path, encoding.encode(&new))?;
+        }
+        record(unresolved, names, shown_as);
+
+        Ok(())
+    }
+}
+
+/// Whether a name is a single normal path component, without separators.
+fn is_plain_name(name: &str) -> bool {
+    let mut components = Path::new(name).components();
+
+    !name.contains(['/', '\\'])
+        && matches!(components.next(), Some(Component::Normal(c)) if c == name)
+        && components.next().is_none()
+}
+
+/// How a text file was stored, so it can be written back the same way.
+/// Line endings are kept by rendering the text as it is.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Encoding {
+    Utf8 { bom: bool },
+    Utf16Le,
+    Utf16Be,
+}
+
+/// How much of a file to look at for NUL bytes, like git does.
+const BINARY_SNIFF_LEN: usize = 8000;
+
+impl Encoding {
+    /// Decodes a text file, or returns None for anything that looks binary.
+    fn decode(bytes: &[u8]) -> Option<(Encoding, String)> {
+        let utf16 = |rest: &[u8], encoding, from: fn([u8; 2]) -> u16| {
+            if rest.len() % 2 != 0 {
+                return None;
+            }
+            let units: Vec<u16> = rest.chunks(2).map(|c| from([c[0], c[1]])).collect();
+            String::from_utf16(&units).ok().map(|text| (encoding, text))
+        };
+
+        if let Some(rest) = bytes.strip_prefix(&[0xFF, 0xFE]) {
+            return utf16(rest, Encoding::Utf16Le, u16::from_le_bytes);
+        }
+        if let Some(rest) = bytes.strip_prefix(&[0xFE, 0xFF]) {
+            return utf16(rest, Encoding::Utf16Be, u16::from_be_bytes);
+        }
+
+        let (bom, rest) = match bytes.strip_prefix(&[0xEF, 0xBB, 0xBF]) {
+            Some(rest) => (true, rest),
+            None => (false, bytes),
+        };
+        if rest[..rest.len().min(BINARY_SNIFF_LEN)].contains(&0) {
+            return None;
+        }
+
+        let text = std::str::from_utf8(rest).ok()?;
+        Some((Encoding::Utf8 { bom }, text.to_owned()))
+    }
+
+    fn encode(self, text: &str) -> Vec<u8> {
+        match self {
+            Encoding::Utf8 { bom } => {
+                let mut bytes = if bom {
+                    vec![0xEF, 0xBB, 0xBF]
+                } else {
+                    Vec::new()
+                };
+                bytes.extend_from_slice(text.as_bytes());
+                bytes
+            }
+            Encoding::Utf16Le => {
+                let mut bytes = vec![0xFF, 0xFE];
+                bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes));
+                bytes
+            }
+            Encoding::Utf16Be => {
+                let mut bytes = vec![0xFE, 0xFF];
+                bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes));
+                bytes
+            }
+        }
+    }
+}
+
+/// The text of a file, or None if it looks binary.
+pub fn decode_text(bytes: &[u8]) -> Option<String> {
+    Encoding::decode(bytes).map(|(_, text)| text)
+}
+
+/// Notes unresolved placeholder names as found at `path`.
+pub fn record(unresolved: &mut Unresolved, names: BTreeSet<String>, path: &Path) {
+    for name in names {
+        unresolved
+            .entry(name)
+            .or_default()
+            .insert(path.to_path_buf());
+    }
+}
+
+/// An open `{{#if}}` block.
+struct Block {
+    /// Whether the branch being read is kept.
+    active: bool,
+    in_else: bool,
+    /// Whether its variable has no value, so its tags are kept as written
+    /// along with both branches.
+    kept: bool,
+    /// Offset of the `{{#if}}` that opened it.
+    start: usize,
+}
+
+impl Block {
+    fn tag(kept: bool) -> Tag {
+        if kept {
+            Tag::Kept
+        } else {
+            Tag::Applied
+        }
+    }
+}
+
+/// What became of a block tag.
+enum Tag {
+    /// Acted on, and left out of the text.
+    Applied,
+    /// Copied as written, as its block's variable has no value.
+    Kept,
+}
+
+fn push(out: &mut String, on: bool, text: &str) {
+    if on {
+        out.push_str(text);
+    }
+}
+
+/// Whether a string can name a variable.
+pub fn is_name(name: &str) -> bool {
+    let mut chars = name.chars();
+
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Parses a `name=value` pair given with `--var`.
+pub fn parse_var(arg: &str) -> Result<(String, String), String> {
+    match arg.split_once('=') {
+        Some((name, value)) if is_name(name) => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(format!(
+            "expected name=value with a name of letters, digits, _ or -, got '{}'",
+            arg
+        )),
+    }
+}
+
+/// Names of the variables `builtin_vars` fills in.
+pub const BUILTIN_NAMES: [&str; 4] = ["project_name", "year", "git_user_name", "git_user_email"];
+
+/// Variables every template gets: the project name from the directory being
+/// scaffolded, the current year, and the git user's name and email when set.
+pub fn builtin_vars(project_dir: &str) -> Vars {
+    let mut vars = Vars::new();
+
+    if let Some(name) = directories::resolve(project_dir).file_name() {
+        vars.insert("project_name".into(), name.to_string_lossy().into_owned());
+    }
+
+    let days = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs() / 86_400)
+        .unwrap_or_default();
+    vars.insert("year".into(), year_of(days as i64).to_string());
+
+    if let Ok(config) = git2::Config::open_default() {
+        for (key, name) in [
+            ("user.name", "git_user_name"),
+            ("user.email", "git_user_email"),
+        ] {
+            if let Ok(value) = config.get_string(key) {
+                vars.insert(name.into(), value);
+            }
+        }
+    }
+
+    vars
+}
+
+/// Values for GitHub's workflow template tokens: `$default-branch`,
+/// `$protected-branches`, `$cron-daily` and `$cron-weekly`. Cron times are
+/// spread by a hash of `seed`, so a repo gets the same times on every run.
+pub fn workflow_vars(default_branch: &str, protected: &[String], seed: &str) -> Vars {
+    let mut vars = Vars::new();
+    vars.insert("default-branch".into(), default_branch.into());
+
+    let protected = match protected {
+        [] => default_branch.to_owned(),
+        branches => branches.join(", "),
+    };
+    vars.insert("protected-branches".into(), protected);
+
+    // FNV-1a, which unlike std's hasher is stable between releases
+    let hash = seed.bytes().fold(0xcbf2_9ce4_8422_2325_u64, |h, b| {
+        (h ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    });
+    let (minute, hour, weekday) = (hash % 60, hash / 60 % 24, hash / 1440 % 7);
+    vars.insert("cron-daily".into(), format!("{} {} * * *", minute, hour));
+    vars.insert(
+        "cron-weekly".into(),
+        format!("{} {} * * {}", minute, hour, weekday),
+    );
+
+    vars
+}
+
+/// Calendar year of a day count since the Unix epoch.
+fn year_of(days: i64) -> i64 {
+    // Howard Hinnant's civil_from_days, with eras starting on March 1st
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+
+    yoe + era * 400 + if mp >= 10 { 1 } else { 0 }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::directories::test_dir;
+
+    fn placeholders(vars: &[(&str, &str)]) -> Placeholders {
+        Placeholders::new(
+            vars.iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        )
+    }
 
     #[test]
     fn it_replaces_branch_placeholder() {
-        let file_name = "tests/sample_templates/android.yml";
-        update_placeholder_branch(file_name, "main").expect("should be able to update test file");
-        let after_contents = read_to_string(file_name).expect("test file not present");
+        let dir = test_dir("it_replaces_branch_placeholder");
+        fs::create_dir_all(&dir).unwrap();
+        let file_name = dir.join("android.yml");
+        fs::copy("tests/sample_templates/android.yml", &file_name).expect("test file not present");
+        let mut unresolved = Unresolved::new();
+        placeholders(&[("default-branch", "main")])
+            .render_path(&file_name, &file_name, &mut unresolved)
+            .expect("should be able to update test file");
+        let after_contents = fs::read_to_string(&file_name).expect("test file not present");
 
         assert_eq!(after_contents.contains("$default-branch"), false);
         assert_eq!(after_contents.contains("main"), true);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_renders_variables() {
+        let p = placeholders(&[("name", "billing"), ("team", "payments")]);
+
+        let (text, unresolved) = p.render("{{name}} by {{ team }} in $name-deploy.yml");
+        assert_eq!(text, "billing by payments in billing-deploy.yml");
+        assert!(unresolved.is_empty());
+
+        let (text, _) = p.render("$names $HOME {{ bad name }} {x}");
+        assert_eq!(text, "$names $HOME {{ bad name }} {x}");
+    }
+
+    #[test]
+    fn it_keeps_builtins_to_braces() {
+        let p = placeholders(&[("year", "2026"), ("project_name", "svc"), ("team", "ci")]);
+
+        let (text, _) = p.render("{{year}} {{project_name}} $year $project_name $team");
+        assert_eq!(text, "2026 svc $year $project_name ci");
+
+        let p = p.with_dollar_names(["year".to_string()]);
+        let (text, _) = p.render("$year $project_name");
+        assert_eq!(text, "2026 $project_name");
+    }
+
+    #[test]
+    fn it_leaves_expressions_and_escapes() {
+        let p = placeholders(&[("name", "billing")]);
+
+        let (text, _) = p.render("${{ github.ref }} \\{{name}} \\$name \\n");
+        assert_eq!(text, "${{ github.ref }} {{name}} $name \\n");
+    }
+
+    #[test]
+    fn it_reports_unresolved_placeholders() {
+        let p = placeholders(&[("name", "billing")]);
+
+        let (text, unresolved) = p.render("{{name}}: {{owner}}, {{ owner }}");
+        assert_eq!(text, "billing: {{owner}}, {{ owner }}");
+        assert_eq!(unresolved.into_iter().collect::<Vec<_>>(), vec!["owner"]);
+    }
+
+    #[test]
+    fn it_renders_conditional_blocks() {
+        let p = placeholders(&[("docker", "true"), ("db", "sqlite")]);
+
+        let (text, _) = p.render(
+            "a\n  {{#if docker}}\n  b\n  {{#if db == postgres}}\n  c\n  {{else}}\n  d\n  {{/if}}\n{{/if}}\ne {{#if !docker}}f{{else}}g{{/if}}",
+        );
+        assert_eq!(text, "a\n  b\n  d\ne g");
+
+        let (text, unresolved) = p.render("{{#if cache}}x {{owner}}{{/if}}{{/if}} {{#if a b}}");
+        assert_eq!(text, "{{#if cache}}x {{owner}}{{/if}}{{/if}} {{#if a b}}");
+        assert_eq!(
+            unresolved.into_iter().collect::<Vec<_>>(),
+            vec!["cache", "owner"]
+        );
+
+        // Handlebars files keep their own blocks, while ones inside still apply
+        let (text, _) =
+            p.render("{{#if user}}\n  hi {{#if docker}}d{{/if}}\n{{else}}\n  bye\n{{/if}}\n");
+        assert_eq!(text, "{{#if user}}\n  hi d\n{{else}}\n  bye\n{{/if}}\n");
+
+        let (text, unresolved) = p.render("a {{#if docker}}b {{#if db}}c{{/if}} d\ne");
+        assert_eq!(text, "a {{#if docker}}b c d\ne");
+        assert_eq!(
+            unresolved.into_iter().collect::<Vec<_>>(),
+            vec!["#if docker"]
+        );
+    }
+
+    #[test]
+    fn it_renders_names() {
+        let p = placeholders(&[("name", "billing")]);
+
+        let (path, unresolved) = p
+            .render_name(Path::new("src/{{name}}/$name-{{kind}}.yml"))
+            .unwrap();
+        assert_eq!(path, PathBuf::from("src/billing/billing-{{kind}}.yml"));
+        assert_eq!(unresolved.into_iter().collect::<Vec<_>>(), vec!["kind"]);
+    }
+
+    #[test]
+    fn it_rejects_names_rendered_outside_the_tree() {
+        for value in ["../../evil", "/tmp/evil", "..", ".", ""] {
+            let p = placeholders(&[("x", value)]);
+            assert!(
+                matches!(
+                    p.render_name(Path::new("src/{{x}}")),
+                    Err(Error::BadRenderedName(..))
+                ),
+                "{:?}",
+                value
+            );
+        }
+
+        let dir = test_dir("it_rejects_names_rendered_outside_the_tree");
+        fs::create_dir_all(dir.join("tree")).unwrap();
+        fs::write(dir.join("tree/{{x}}"), "evil").unwrap();
+
+        let p = placeholders(&[("x", "../evil")]);
+        let res = p.render_path(&dir.join("tree"), &dir.join("tree"), &mut Unresolved::new());
+        assert!(matches!(res, Err(Error::BadRenderedName(..))));
+        assert!(!dir.join("evil").exists());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_encodings_and_skips_binaries() {
+        let p = placeholders(&[("name", "billing")]);
+        let dir = test_dir("it_keeps_encodings_and_skips_binaries");
+        fs::create_dir_all(&dir).unwrap();
+
+        let utf16: Vec<u8> = Encoding::Utf16Le.encode("{{name}}\r\n");
+        let files: Vec<(&str, Vec<u8>, Vec<u8>)> = vec![
+            (
+                "bom.txt",
+                b"\xEF\xBB\xBF{{name}}\r\nx\r\n".to_vec(),
+                b"\xEF\xBB\xBFbilling\r\nx\r\n".to_vec(),
+            ),
+            ("utf16.txt", utf16, Encoding::Utf16Le.encode("billing\r\n")),
+            (
+                "logo.png",
+                b"\x89PNG\0{{name}}".to_vec(),
+                b"\x89PNG\0{{name}}".to_vec(),
+            ),
+            (
+                "latin1.txt",
+                b"caf\xE9 {{name}}".to_vec(),
+                b"caf\xE9 {{name}}".to_vec(),
+            ),
+        ];
+        for (name, before, _) in &files {
+            fs::write(dir.join(name), before).unwrap();
+        }
+
+        p.render_path(&dir, &dir, &mut Unresolved::new()).unwrap();
+
+        for (name, _, after) in &files {
+            assert_eq!(&fs::read(dir.join(name)).unwrap(), after, "{}", name);
+        }
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_renders_only_listed_extensions() {
+        let p = placeholders(&[("name", "billing")]).with_extensions(&[".YML".into()]);
+        let dir = test_dir("it_renders_only_listed_extensions");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("ci.yml"), "{{name}}").unwrap();
+        fs::write(dir.join("main.rs"), "{{name}}").unwrap();
+
+        p.render_path(&dir, &dir, &mut Unresolved::new()).unwrap();
+
+        assert_eq!(fs::read_to_string(dir.join("ci.yml")).unwrap(), "billing");
+        assert_eq!(fs::read_to_string(dir.join("main.rs")).unwrap(), "{{name}}");
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn it_parses_vars() {
+        assert_eq!(
+            parse_var("team=a=b"),
+            Ok(("team".to_owned(), "a=b".to_owned()))
+        );
+        assert!(parse_var("team").is_err());
+        assert!(parse_var("1team=x").is_err());
+    }
+
+    #[test]
+    fn it_fills_workflow_tokens() {
+        let vars = workflow_vars("main", &[], "git@github.com:owner/repo.git");
+        assert_eq!(vars["protected-branches"], "main");
+        assert_eq!(
+            vars,
+            workflow_vars("main", &[], "git@github.com:owner/repo.git")
+        );
+
+        let cron: Vec<&str> = vars["cron-weekly"].split(' ').collect();
+        assert!(cron[0].parse::<u8>().unwrap() < 60);
+        assert!(cron[1].parse::<u8>().unwrap() < 24);
+        assert!(cron[4].parse::<u8>().unwrap() < 7);
+        assert!(vars["cron-daily"].ends_with(" * * *"));
+
+        let vars = workflow_vars("main", &["main".into(), "release".into()], "x");
+        let (text, _) =
+            Placeholders::new(vars).render("branches: [ $default-branch, $protected-branches ]");
+        assert_eq!(text, "branches: [ main, main, release ]");
+    }
+
+    #[test]
+    fn it_finds_years() {
+        assert_eq!(year_of(0), 1970);
+        assert_eq!(year_of(10_957), 2000);
+        assert_eq!(year_of(10_956), 1999);
+        assert_eq!(year_of(20_819), 2027);
     }
 }
@@ -0,0 +1,200 @@
+// Asks for template variable values, conflict resolutions and deletes in the
+// terminal
+use crate::directories::OnConflict;
+use crate::errors;
+use crate::manifest::Variable;
+use console::style;
+use errors::{AppResult, Error};
+use std::io::{BufRead, Write};
+use std::path::Path;
+
+/// Prompts until a valid value is entered. An empty answer takes the
+/// default, and a choice can be picked by its number.
+pub fn ask<R: BufRead, W: Write>(
+    var: &Variable,
+    default: Option<&str>,
+    input: &mut R,
+    output: &mut W,
+) -> AppResult<String> {
+    loop {
+        write!(output, "{}", style(&var.name).bold())?;
+        if let Some(d) = &var.description {
+            write!(output, " ({})", d)?;
+        }
+        for (i, choice) in var.choices.iter().enumerate() {
+            write!(output, "\n  {}) {}", i + 1, choice)?;
+        }
+        if let Some(d) = default {
+            write!(output, " [{}]", style(d).dim())?;
+        }
+        write!(output, ": ")?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            // stdin closed before an answer
+            return Err(Error::MissingVariable(
+                var.name.clone(),
+                var.description.clone(),
+            ));
+        }
+
+        let answer = line.trim();
+        let value = match (answer, default) {
+            ("", Some(d)) => d.to_owned(),
+            ("", None) => {
+                writeln!(output, "{}", style("A value is required.").red())?;
+                continue;
+            }
+            (a, _) => match a.parse::<usize>() {
+                Ok(n) if n >= 1 && n <= var.choices.len() => var.choices[n - 1].clone(),
+                _ => a.to_owned(),
+            },
+        };
+
+        match var.validate(&value) {
+            Ok(()) => return Ok(value),
+            Err(msg) => writeln!(output, "{}", style(format!("Value {}.", msg)).red())?,
+        }
+    }
+}
+
+/// Asks what to do with a file already in the destination. An uppercase
+/// answer also applies to every conflict after it, as does the end of input,
+/// which skips them.
+pub fn ask_conflict<R: BufRead, W: Write>(
+    path: &Path,
+    input: &mut R,
+    output: &mut W,
+) -> AppResult<(OnConflict, bool)> {
+    loop {
+        write!(
+            output,
+            "{} already exists. [s]kip, [o]verwrite, [b]ackup, [r]ename (uppercase for all): ",
+            style(path.display()).bold()
+        )?;
+        output.flush()?;
+
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok((OnConflict::Skip, true));
+        }
+
+        let answer = line.trim();
+        let choice = match answer.to_ascii_lowercase().as_str() {
+            "s" => OnConflict::Skip,
+            "o" => OnConflict::Overwrite,
+            "b" => OnConflict::Backup,
+            "r" => OnConflict::Rename,
+            _ => {
+                writeln!(output, "{}", style("Answer s, o, b or r.").red())?;
+                continue;
+            }
+        };
+
+        return Ok((choice, answer.chars().all(|c| c.is_ascii_uppercase())));
+    }
+}
+
+/// Asks whether to delete a path the template lists but rsieve didn't
+/// write. Anything but yes, including the end of input, keeps it.
+pub fn confirm_delete<R: BufRead, W: Write>(
+    path: &Path,
+    input: &mut R,
+    output: &mut W,
+) -> AppResult<bool> {
+    write!(
+        output,
+        "The template deletes {}, which rsieve didn't write. Delete it? [y/N]: ",
+        style(path.display()).bold()
+    )?;
+    output.flush()?;
+
+    let mut line = String::new();
+    input.read_line(&mut line)?;
+
+    Ok(matches!(
+        line.trim().to_ascii_lowercase().as_str(),
+        "y" | "yes"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::Manifest;
+    use std::io::Cursor;
+
+    fn variables() -> Vec<Variable> {
+        Manifest::from_toml(
+            r#"
+            [[variables]]
+            name = "team"
+            pattern = "[a-z]+"
+
+            [[variables]]
+            name = "region"
+            choices = ["us", "eu"]
+            "#,
+        )
+        .unwrap()
+        .variables
+    }
+
+    fn answer(var: &Variable, default: Option<&str>, input: &str) -> AppResult<String> {
+        ask(var, default, &mut Cursor::new(input), &mut Vec::new())
+    }
+
+    #[test]
+    fn it_takes_defaults_and_answers() {
+        let vars = variables();
+
+        assert_eq!(answer(&vars[0], Some("web"), "\n").unwrap(), "web");
+        assert_eq!(answer(&vars[0], Some("web"), " api \n").unwrap(), "api");
+    }
+
+    #[test]
+    fn it_asks_again_until_valid() {
+        let vars = variables();
+
+        assert_eq!(answer(&vars[0], None, "\nApi\napi\n").unwrap(), "api");
+        assert_eq!(answer(&vars[1], None, "3\nap\n2\n").unwrap(), "eu");
+    }
+
+    #[test]
+    fn it_fails_when_input_ends() {
+        let vars = variables();
+
+        assert!(matches!(
+            answer(&vars[0], None, "Api\n"),
+            Err(Error::MissingVariable(..))
+        ));
+    }
+
+    #[test]
+    fn it_asks_how_to_settle_conflicts() {
+        let ask = |input: &str| {
+            ask_conflict(
+                Path::new("ci.yml"),
+                &mut Cursor::new(input),
+                &mut Vec::new(),
+            )
+            .unwrap()
+        };
+
+        assert_eq!(ask("x\nb\n"), (OnConflict::Backup, false));
+        assert_eq!(ask("O\n"), (OnConflict::Overwrite, true));
+        assert_eq!(ask(""), (OnConflict::Skip, true));
+    }
+
+    #[test]
+    fn it_keeps_paths_unless_told_to_delete() {
+        let confirm = |input: &str| {
+            confirm_delete(Path::new("src"), &mut Cursor::new(input), &mut Vec::new()).unwrap()
+        };
+
+        assert!(confirm("y\n"));
+        assert!(!confirm("\n"));
+        assert!(!confirm(""));
+    }
+}
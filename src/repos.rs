@@ -1,55 +1,157 @@
+use crate::config;
 use crate::errors;
 use regex::Regex;
+use serde::Deserialize;
 
+use config::Config;
 use errors::{AppResult, Error};
 
+#[derive(Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+pub enum Forge {
+    GitHub,
+    GitLab,
+    #[serde(alias = "forgejo")]
+    Gitea,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum GitRef {
+    Branch(String),
+    /// Tag, full or short commit SHA, or any other name the forge resolves.
+    Rev(String),
+}
+
+impl GitRef {
+    pub fn name(&self) -> &str {
+        match self {
+            GitRef::Branch(name) | GitRef::Rev(name) => name,
+        }
+    }
+}
+
 #[derive(Debug, PartialEq, Eq)]
 pub struct RepoMeta {
     pub protocol: String,
+    pub host: String,
+    pub base_url: String,
+    pub forge: Forge,
     pub url_stem: String,
+    pub subdir: Option<String>,
+    pub git_ref: Option<GitRef>,
 }
 
-pub fn parse_repo_input(repo_input: &str) -> AppResult<RepoMeta> {
-    let re = Regex::new(
-        r"(?x)
-            (?P<protocol>(git@|https://))?
-            (?P<host>([\w\.@]+))?
-            (/|:|^)
-            (?P<owner>[\w,\-,_]+)
-            /
-            (?P<repo>[\w,\-,_]+)
-            (.git)?/?
-            ",
-    )
-    .expect("Regular expression invalid.");
-
-    if re.is_match(repo_input) {
-        let caps = re.captures(repo_input).unwrap();
-        let protocol = match caps.name("protocol") {
-            Some(p) => p.as_str().to_owned(),
-            None => "https".into(),
-        };
-
-        if caps.name("host").is_some() && caps.name("host").unwrap().as_str() != "github.com" {
-            return Err(Error::BadHost);
+pub fn parse_repo_input(repo_input: &str, config: &Config) -> AppResult<RepoMeta> {
+    let segment = Regex::new(r"^[\w\-\.]+$").expect("Regular expression invalid.");
+
+    // a trailing `#ref` pins a tag, commit or branch
+    let (repo_input, pinned_ref) = match repo_input.rsplit_once('#') {
+        Some((_, "")) => return Err(Error::BadInput),
+        Some((input, r)) => (input, Some(r)),
+        None => (repo_input, None),
+    };
+
+    let (protocol, rest) = if let Some(rest) = repo_input.strip_prefix("https://") {
+        ("https://", rest)
+    } else if let Some(rest) = repo_input.strip_prefix("git@") {
+        ("git@", rest)
+    } else {
+        ("https", repo_input)
+    };
+
+    // split off the host, falling back to shorthands and bare GitHub stems
+    let (host, path) = match protocol {
+        "git@" => match rest.split_once(':') {
+            Some((host, path)) => (host, path),
+            None => return Err(Error::BadInput),
+        },
+        "https://" => rest.split_once('/').unwrap_or((rest, "")),
+        _ => {
+            if let Some(path) = rest.strip_prefix("gitlab:") {
+                ("gitlab.com", path)
+            } else if let Some(path) = rest.strip_prefix("github:") {
+                ("github.com", path)
+            } else {
+                match rest.split_once('/') {
+                    Some((host, path)) if host.contains('.') => (host, path),
+                    _ => ("github.com", rest),
+                }
+            }
         }
+    };
 
-        let owner = match caps.name("owner") {
-            Some(o) => o.as_str().to_owned(),
-            None => return Err(Error::BadOwner),
-        };
+    let host_config = match config.host(host) {
+        Some(h) => h,
+        None => return Err(Error::BadHost(host.into())),
+    };
+    let forge = host_config.forge;
+    let base_url = match host_config.base_url {
+        Some(b) => b.trim_end_matches('/').to_owned(),
+        None => format!("https://{}", host),
+    };
 
-        let repo = match caps.name("repo") {
-            Some(r) => r.as_str().to_owned(),
-            None => return Err(Error::BadRepo),
-        };
+    let segments: Vec<&str> = path.trim_end_matches('/').split('/').collect();
 
-        Ok(RepoMeta {
-            protocol,
-            url_stem: format!("{}/{}", owner, repo),
-        })
-    } else {
-        Err(Error::BadInput)
+    // GitLab marks the end of the project path with a `-` segment
+    let (mut project, extra) = match forge {
+        Forge::GitLab => match segments.iter().position(|s| *s == "-") {
+            Some(i) => (segments[..i].to_vec(), &segments[i + 1..]),
+            None => (segments.clone(), &[][..]),
+        },
+        _ => (
+            segments.iter().take(2).copied().collect(),
+            segments.get(2..).unwrap_or(&[]),
+        ),
+    };
+
+    if let Some(last) = project.last_mut() {
+        *last = last.strip_suffix(".git").unwrap_or(last);
+    }
+
+    let (tree_ref, subdir) = split_tree_path(forge, extra)?;
+
+    if project
+        .iter()
+        .chain(subdir.iter())
+        .any(|s| !s.is_empty() && (!segment.is_match(s) || *s == "." || *s == ".."))
+    {
+        return Err(Error::BadInput);
+    }
+
+    match project.as_slice() {
+        [] | [""] => Err(Error::BadOwner),
+        [_] | [_, ""] => Err(Error::BadRepo),
+        _ if project.contains(&"") || subdir.contains(&"") => Err(Error::BadInput),
+        _ => Ok(RepoMeta {
+            protocol: protocol.into(),
+            host: host.into(),
+            base_url,
+            forge,
+            url_stem: project.join("/"),
+            subdir: if subdir.is_empty() {
+                None
+            } else {
+                Some(subdir.join("/"))
+            },
+            git_ref: pinned_ref.or(tree_ref).map(|r| GitRef::Rev(r.into())),
+        }),
+    }
+}
+
+/// Splits what follows the project path into a ref and a subdirectory, based
+/// on each forge's browser URLs. Refs containing slashes can't be told apart
+/// from the path, so the first segment is always taken as the ref.
+fn split_tree_path<'a>(
+    forge: Forge,
+    extra: &'a [&'a str],
+) -> AppResult<(Option<&'a str>, &'a [&'a str])> {
+    match (forge, extra) {
+        (_, []) => Ok((None, extra)),
+        (Forge::GitHub, ["tree", r, rest @ ..]) => Ok((Some(*r), rest)),
+        (Forge::GitLab, ["tree", r, rest @ ..]) => Ok((Some(*r), rest)),
+        (Forge::Gitea, ["src", "branch" | "tag" | "commit", r, rest @ ..]) => Ok((Some(*r), rest)),
+        (Forge::GitLab, _) => Err(Error::BadInput),
+        _ => Ok((None, extra)),
     }
 }
 
@@ -60,13 +162,18 @@ mod tests {
     #[test]
     fn it_parses_stem_repos() {
         let repo = "bradyjoslin/sharewifi";
-        let repo_meta = parse_repo_input(repo).unwrap();
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
 
         assert_eq!(
             repo_meta,
             RepoMeta {
                 protocol: "https".into(),
+                host: "github.com".into(),
+                base_url: "https://github.com".into(),
+                forge: Forge::GitHub,
                 url_stem: "bradyjoslin/sharewifi".into(),
+                subdir: None,
+                git_ref: None,
             }
         );
     }
@@ -74,13 +181,18 @@ mod tests {
     #[test]
     fn it_parses_full_repos() {
         let repo = "https://github.com/bradyjoslin/sharewifi";
-        let repo_meta = parse_repo_input(repo).unwrap();
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
 
         assert_eq!(
             repo_meta,
             RepoMeta {
                 protocol: "https://".into(),
+                host: "github.com".into(),
+                base_url: "https://github.com".into(),
+                forge: Forge::GitHub,
                 url_stem: "bradyjoslin/sharewifi".into(),
+                subdir: None,
+                git_ref: None,
             }
         );
     }
@@ -88,13 +200,18 @@ mod tests {
     #[test]
     fn it_parses_git_repos() {
         let repo = "git@github.com:bradyjoslin/sharewifi.git";
-        let repo_meta = parse_repo_input(repo).unwrap();
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
 
         assert_eq!(
             repo_meta,
             RepoMeta {
                 protocol: "git@".into(),
+                host: "github.com".into(),
+                base_url: "https://github.com".into(),
+                forge: Forge::GitHub,
                 url_stem: "bradyjoslin/sharewifi".into(),
+                subdir: None,
+                git_ref: None,
             }
         );
     }
@@ -102,7 +219,7 @@ mod tests {
     #[test]
     fn it_only_parses_github_git_repos() {
         let repo = "git@githubs.com:bradyjoslin/sharewifi.git";
-        let repo_meta = parse_repo_input(repo);
+        let repo_meta = parse_repo_input(repo, &Config::default());
 
         assert_eq!(repo_meta.is_err(), true);
     }
@@ -110,8 +227,164 @@ mod tests {
     #[test]
     fn it_only_parses_github_http_repos() {
         let repo = "https://githubs.com/bradyjoslin/sharewifi";
-        let repo_meta = parse_repo_input(repo);
+        let repo_meta = parse_repo_input(repo, &Config::default());
 
         assert_eq!(repo_meta.is_err(), true);
     }
+
+    #[test]
+    fn it_parses_gitlab_shorthand_repos() {
+        let repo = "gitlab:group/subgroup/project";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(
+            repo_meta,
+            RepoMeta {
+                protocol: "https".into(),
+                host: "gitlab.com".into(),
+                base_url: "https://gitlab.com".into(),
+                forge: Forge::GitLab,
+                url_stem: "group/subgroup/project".into(),
+                subdir: None,
+                git_ref: None,
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_full_gitlab_repos() {
+        let repo = "https://gitlab.com/group/subgroup/project";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(
+            repo_meta,
+            RepoMeta {
+                protocol: "https://".into(),
+                host: "gitlab.com".into(),
+                base_url: "https://gitlab.com".into(),
+                forge: Forge::GitLab,
+                url_stem: "group/subgroup/project".into(),
+                subdir: None,
+                git_ref: None,
+            }
+        );
+    }
+
+    #[test]
+    fn it_parses_gitlab_git_repos() {
+        let repo = "git@gitlab.com:group/project.git";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(
+            repo_meta,
+            RepoMeta {
+                protocol: "git@".into(),
+                host: "gitlab.com".into(),
+                base_url: "https://gitlab.com".into(),
+                forge: Forge::GitLab,
+                url_stem: "group/project".into(),
+                subdir: None,
+                git_ref: None,
+            }
+        );
+    }
+
+    #[test]
+    fn it_requires_a_repo() {
+        let repo = "gitlab:group";
+        let repo_meta = parse_repo_input(repo, &Config::default());
+
+        assert!(repo_meta.is_err());
+    }
+
+    #[test]
+    fn it_parses_configured_hosts() {
+        let config = Config::from_toml(
+            r#"
+            [hosts."ghe.corp.example"]
+            forge = "github"
+
+            [hosts."code.corp.example"]
+            forge = "gitea"
+            base_url = "https://code.corp.example/"
+            "#,
+        )
+        .unwrap();
+
+        let repo_meta = parse_repo_input("ghe.corp.example/team/repo", &config).unwrap();
+        assert_eq!(
+            repo_meta,
+            RepoMeta {
+                protocol: "https".into(),
+                host: "ghe.corp.example".into(),
+                base_url: "https://ghe.corp.example".into(),
+                forge: Forge::GitHub,
+                url_stem: "team/repo".into(),
+                subdir: None,
+                git_ref: None,
+            }
+        );
+
+        let repo_meta = parse_repo_input("git@code.corp.example:team/repo.git", &config).unwrap();
+        assert_eq!(repo_meta.forge, Forge::Gitea);
+        assert_eq!(repo_meta.base_url, "https://code.corp.example");
+    }
+
+    #[test]
+    fn it_parses_stem_subdirectories() {
+        let repo = "bradyjoslin/rsieve/tests/sample_templates";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(repo_meta.url_stem, "bradyjoslin/rsieve");
+        assert_eq!(repo_meta.subdir.as_deref(), Some("tests/sample_templates"));
+        assert_eq!(repo_meta.git_ref, None);
+    }
+
+    #[test]
+    fn it_parses_tree_urls() {
+        let repo = "https://github.com/actions/starter-workflows/tree/main/ci";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(repo_meta.url_stem, "actions/starter-workflows");
+        assert_eq!(repo_meta.subdir.as_deref(), Some("ci"));
+        assert_eq!(repo_meta.git_ref, Some(GitRef::Rev("main".into())));
+
+        let repo = "https://gitlab.com/group/subgroup/project/-/tree/v1.0/templates/web";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(repo_meta.url_stem, "group/subgroup/project");
+        assert_eq!(repo_meta.subdir.as_deref(), Some("templates/web"));
+        assert_eq!(repo_meta.git_ref, Some(GitRef::Rev("v1.0".into())));
+    }
+
+    #[test]
+    fn it_rejects_parent_subdirectories() {
+        let repo = "bradyjoslin/rsieve/../secrets";
+        let repo_meta = parse_repo_input(repo, &Config::default());
+
+        assert!(repo_meta.is_err());
+    }
+
+    #[test]
+    fn it_parses_pinned_refs() {
+        let repo = "bradyjoslin/sharewifi#v1.2.0";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(repo_meta.url_stem, "bradyjoslin/sharewifi");
+        assert_eq!(repo_meta.git_ref, Some(GitRef::Rev("v1.2.0".into())));
+
+        let repo = "https://github.com/actions/starter-workflows/tree/main/ci#a1b2c3d";
+        let repo_meta = parse_repo_input(repo, &Config::default()).unwrap();
+
+        assert_eq!(repo_meta.subdir.as_deref(), Some("ci"));
+        assert_eq!(repo_meta.git_ref, Some(GitRef::Rev("a1b2c3d".into())));
+    }
+
+    #[test]
+    fn it_rejects_empty_refs() {
+        let repo = "bradyjoslin/sharewifi#";
+        let repo_meta = parse_repo_input(repo, &Config::default());
+
+        assert!(repo_meta.is_err());
+    }
 }
@@ -0,0 +1,531 @@
+// Re-syncs a destination with the upstream it was copied from
+use crate::app::Update;
+use crate::auth;
+use crate::config::Config;
+use crate::directories;
+use crate::downloaders;
+use crate::errors;
+use crate::filters::{self, PathFilter};
+use crate::git;
+use crate::lockfile::{self, Lock};
+use crate::manifest::Manifest;
+use crate::placeholders::{self, decode_text, Placeholders, Unresolved, Vars};
+use crate::repos::{GitRef, RepoMeta};
+use console::{style, user_attended};
+use errors::{AppResult, Error};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::io::{self, IsTerminal};
+use std::path::{Path, PathBuf};
+
+/// What an update did to a file in the destination.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub enum Synced {
+    /// New upstream.
+    Added,
+    /// Replaced, as it hadn't been changed locally.
+    Updated,
+    /// Changed both locally and upstream, and merged cleanly.
+    Merged,
+    /// Changed both locally and upstream, with conflict markers left in.
+    Conflict,
+    /// Changed both locally and upstream but couldn't be merged, so the
+    /// upstream version was written to this path instead.
+    Renamed(PathBuf),
+    /// Changed locally only, so kept as it is.
+    Kept,
+    /// Gone upstream, and removed as it hadn't been changed locally.
+    Removed,
+}
+
+pub struct SyncReport {
+    pub files: Vec<(PathBuf, Synced)>,
+    /// Hash of the upstream version of each file rsieve manages, to lock.
+    pub hashes: BTreeMap<String, String>,
+}
+
+pub async fn update(args: &Update, config: &Config) -> AppResult<()> {
+    let destination = args.destination.as_str();
+    let mut lock = Lock::read(destination)?.ok_or_else(|| Error::NoLock(destination.into()))?;
+
+    let repo = lock.source.repo_meta(config)?;
+    let token = auth::token_for(&repo, config);
+    let git_ref = match (&args.git_ref, &args.branch) {
+        (Some(r), _) => Some(GitRef::Rev(r.clone())),
+        (None, Some(b)) => Some(GitRef::Branch(b.clone())),
+        (None, None) => lock.source.git_ref(),
+    };
+
+    let interactive = !args.no_input && user_attended() && io::stdin().is_terminal();
+
+    let work_dir = PathBuf::from(directories::prep_tmp_dir()?);
+    println!(
+        "Getting {}/{}{}...",
+        lock.source.host,
+        lock.source.repo,
+        git_ref
+            .as_ref()
+            .map(|r| format!("#{}", r.name()))
+            .unwrap_or_default()
+    );
+    let upstream = work_dir.join("upstream");
+    let (commit, unresolved) = snapshot(
+        &mut lock,
+        interactive,
+        destination,
+        &repo,
+        git_ref.clone(),
+        token.as_deref(),
+        &upstream,
+    )
+    .await?;
+
+    // the base is what was copied last time, fetched again at its commit
+    let base = match lock.source.commit.clone() {
+        Some(c) if commit.as_ref() == Some(&c) => Some(upstream.join("out")),
+        Some(c) => {
+            let base = work_dir.join("base");
+            let rev = Some(GitRef::Rev(c));
+            snapshot(
+                &mut lock,
+                interactive,
+                destination,
+                &repo,
+                rev,
+                token.as_deref(),
+                &base,
+            )
+            .await?;
+            Some(base.join("out"))
+        }
+        None => None,
+    };
+
+    for (name, paths) in &unresolved {
+        let paths: Vec<String> = paths
+            .iter()
+            .map(|p| match p.strip_prefix(upstream.join("out")) {
+                Ok(rel_path) => Path::new(destination).join(rel_path),
+                Err(_) => p.clone(),
+            })
+            .map(|p| p.display().to_string())
+            .collect();
+        println!(
+            "{} {{{{{}}}}} left unresolved in {}",
+            style("warning:").yellow().bold(),
+            name,
+            paths.join(", ")
+        );
+    }
+
+    let report = sync(
+        &upstream.join("out"),
+        base.as_deref(),
+        Path::new(destination),
+        &lock.files,
+    )?;
+
+    for (path, synced) in &report.files {
+        let (label, moved) = match synced {
+            Synced::Added => (style("added").green(), None),
+            Synced::Updated => (style("updated").green(), None),
+            Synced::Merged => (style("merged").cyan(), None),
+            Synced::Conflict => (style("conflict").red(), None),
+            Synced::Renamed(renamed) => (style("conflict").red(), Some(renamed)),
+            Synced::Kept => (style("kept").dim(), None),
+            Synced::Removed => (style("removed").yellow(), None),
+        };
+        match moved {
+            Some(to) => println!("  {:>8} {} -> {}", label, path.display(), to.display()),
+            None => println!("  {:>8} {}", label, path.display()),
+        }
+    }
+    let conflicts = report
+        .files
+        .iter()
+        .filter(|(_, s)| matches!(s, Synced::Conflict | Synced::Renamed(_)))
+        .count();
+    if conflicts > 0 {
+        println!(
+            "{} {} file(s) changed both locally and upstream need resolving",
+            style("warning:").yellow().bold(),
+            conflicts
+        );
+    }
+
+    let renamed = report
+        .files
+        .iter()
+        .any(|(_, s)| matches!(s, Synced::Renamed(_)));
+    if base.is_none() && renamed {
+        println!(
+            "{} no commit was locked, so local changes couldn't be merged and the upstream versions were written next to them",
+            style("warning:").yellow().bold(),
+        );
+    }
+
+    lock.source.set_git_ref(git_ref.as_ref());
+    lock.source.commit = commit;
+    lock.files = report.hashes;
+    lock.write(destination)?;
+
+    println!(
+        "Updated {} to {}",
+        destination,
+        lock.source
+            .commit
+            .as_deref()
+            .unwrap_or("the latest upstream")
+    );
+
+    Ok(())
+}
+
+/// Fetches the locked source at `git_ref` into `dir` and lays it out under
+/// `dir/out` as it would be copied, rendered with the locked variables.
+/// Variables the template declares that weren't locked are resolved as a
+/// copy would resolve them, and added to the lock.
+async fn snapshot(
+    lock: &mut Lock,
+    interactive: bool,
+    destination: &str,
+    repo: &RepoMeta,
+    git_ref: Option<GitRef>,
+    token: Option<&str>,
+    dir: &Path,
+) -> AppResult<(Option<String>, Unresolved)> {
+    let fetch_dir = dir.join("fetch").display().to_string();
+    let filters = &lock.filters;
+    let path_filter = PathFilter::new(&repo.subdir, filters.filter.as_deref())?.with_rules(
+        &filters.include,
+        &filters.exclude,
+        filters.ignore_file_in(destination).as_deref(),
+    )?;
+
+    let commit = if lock.source.git {
+        let patterns = git::sparse_patterns(&repo.subdir, filters.filter.as_deref());
+        let commit =
+            downloaders::git_clone(repo, &fetch_dir, git_ref, token, &patterns, &path_filter)?;
+        Some(commit)
+    } else {
+        downloaders::get_tarball(
+            repo,
+            &fetch_dir,
+            git_ref,
+            token,
+            &path_filter,
+            filters.symlinks,
+        )
+        .await?
+    };
+
+    let source = directories::source_dir(&fetch_dir, &repo.subdir)?;
+    let manifest = Manifest::take(Path::new(&source))?.unwrap_or_default();
+    if let Some(f) = manifest.file_filter()? {
+        filters::prune(Path::new(&source), &f)?;
+    }
+    resolve_new_vars(&manifest, &mut lock.vars, destination, interactive)?;
+    manifest.apply_conditions(Path::new(&source), &lock.vars)?;
+
+    let placeholders = if lock.vars.is_empty() {
+        None
+    } else {
+        let declared = manifest.variables.iter().map(|v| v.name.clone());
+        let mut placeholders = Placeholders::new(lock.vars.clone())
+            .with_dollar_names(filters.dollar_names.iter().cloned())
+            .with_dollar_names(declared);
+        if let Some(f) = manifest.render_filter()? {
+            placeholders = placeholders.with_files(Path::new(&source), f);
+        }
+        let extensions = if filters.render_exts.is_empty() {
+            &manifest.files.extensions
+        } else {
+            &filters.render_exts
+        };
+        if !extensions.is_empty() {
+            placeholders = placeholders.with_extensions(extensions);
+        }
+        Some(placeholders)
+    };
+
+    let report = directories::move_to_destination(
+        &source,
+        &dir.join("out").display().to_string(),
+        filters.filter.clone(),
+        None,
+        placeholders.as_ref(),
+        filters.layout(),
+        None,
+    )?;
+
+    Ok((commit, report.unresolved))
+}
+
+/// Fills in variables the template declares that `vars` has no value for,
+/// starting from the built-ins when nothing was rendered before.
+fn resolve_new_vars(
+    manifest: &Manifest,
+    vars: &mut Vars,
+    destination: &str,
+    interactive: bool,
+) -> AppResult<()> {
+    let templated = !manifest.variables.is_empty() || !manifest.conditions.is_empty();
+    if templated && vars.is_empty() {
+        *vars = placeholders::builtin_vars(destination);
+    }
+
+    manifest.resolve_vars(vars, interactive)
+}
+
+/// Brings the files under `upstream` into `destination`. Files that still
+/// match their locked hash are replaced, and ones changed locally are merged
+/// with the upstream changes since `base`.
+pub fn sync(
+    upstream: &Path,
+    base: Option<&Path>,
+    destination: &Path,
+    locked: &BTreeMap<String, String>,
+) -> AppResult<SyncReport> {
+    let mut upstream_files = Vec::new();
+    list_files(upstream, Path::new(""), &mut upstream_files)?;
+
+    let mut synced = Vec::new();
+    let mut hashes = BTreeMap::new();
+    let mut seen = BTreeSet::new();
+
+    for rel_path in upstream_files {
+        let key = lockfile::lock_path(&rel_path);
+        let theirs = fs::read(upstream.join(&rel_path))?;
+        let path = destination.join(&rel_path);
+        seen.insert(key.clone());
+
+        let ours = match fs::symlink_metadata(&path) {
+            Ok(m) if m.is_file() => fs::read(&path)?,
+            Ok(_) => continue,
+            // removed locally since the last copy, so left out, but still
+            // locked so it stays that way
+            Err(_) if locked.contains_key(&key) => {
+                hashes.insert(key, lockfile::hash(&theirs));
+                continue;
+            }
+            Err(_) => {
+                if let Some(parent) = path.parent() {
+                    fs::create_dir_all(parent)?;
+                }
+                fs::write(&path, &theirs)?;
+                hashes.insert(key, lockfile::hash(&theirs));
+                synced.push((path, Synced::Added));
+                continue;
+            }
+        };
+
+        // not copied by rsieve, so not its to change
+        if !locked.contains_key(&key) {
+            synced.push((path, Synced::Kept));
+            continue;
+        }
+        hashes.insert(key.clone(), lockfile::hash(&theirs));
+
+        if ours == theirs {
+            continue;
+        }
+        if locked.get(&key) == Some(&lockfile::hash(&ours)) {
+            fs::write(&path, &theirs)?;
+            synced.push((path, Synced::Updated));
+            continue;
+        }
+
+        let base = match base.map(|b| b.join(&rel_path)) {
+            Some(b) if b.is_file() => Some(fs::read(b)?),
+            _ => None,
+        };
+        if base.as_ref() == Some(&theirs) {
+            synced.push((path, Synced::Kept));
+            continue;
+        }
+
+        let texts = (
+            base.as_deref().and_then(decode_text),
+            decode_text(&ours),
+            decode_text(&theirs),
+        );
+        let outcome = match texts {
+            (Some(base), Some(ours), Some(theirs)) => match diffy::merge(&base, &ours, &theirs) {
+                Ok(merged) => {
+                    fs::write(&path, merged)?;
+                    Synced::Merged
+                }
+                Err(with_markers) => {
+                    fs::write(&path, with_markers)?;
+                    Synced::Conflict
+                }
+            },
+            _ => {
+                let renamed = directories::free_path(&path, "new");
+                fs::write(&renamed, &theirs)?;
+                Synced::Renamed(renamed)
+            }
+        };
+        synced.push((path, outcome));
+    }
+
+    // gone upstream
+    for (key, hash) in locked {
+        if seen.contains(key) {
+            continue;
+        }
+        let path = destination.join(key);
+        match fs::read(&path) {
+            Ok(ours) if lockfile::hash(&ours) == *hash => {
+                fs::remove_file(&path)?;
+                synced.push((path, Synced::Removed));
+            }
+            Ok(_) => synced.push((path, Synced::Kept)),
+            Err(_) => {}
+        }
+    }
+    synced.sort();
+
+    Ok(SyncReport {
+        files: synced,
+        hashes,
+    })
+}
+
+/// Collects the paths of the files under `root`, relative to it.
+fn list_files(root: &Path, rel_path: &Path, files: &mut Vec<PathBuf>) -> AppResult<()> {
+    for entry in fs::read_dir(root.join(rel_path))? {
+        let entry = entry?;
+        let rel_path = rel_path.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            list_files(root, &rel_path, files)?;
+        } else if entry.file_type()?.is_file() {
+            files.push(rel_path);
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    fn write_all(root: &Path, files: &[(&str, &str)]) {
+        for (path, contents) in files {
+            fs::create_dir_all(root.join(path).parent().unwrap()).unwrap();
+            fs::write(root.join(path), contents).unwrap();
+        }
+    }
+
+    #[test]
+    fn it_resolves_variables_added_upstream() {
+        let manifest = Manifest::from_toml(
+            "[[variables]]\nname = \"team\"\n\n[[variables]]\nname = \"region\"\ndefault = \"{{team}}-east\"",
+        )
+        .unwrap();
+
+        let mut vars = Vars::new();
+        vars.insert("team".into(), "payments".into());
+        resolve_new_vars(&manifest, &mut vars, ".", false).unwrap();
+        assert_eq!(vars["region"], "payments-east");
+
+        let mut vars = Vars::new();
+        vars.insert("project_name".into(), "svc".into());
+        let res = resolve_new_vars(&manifest, &mut vars, ".", false);
+        assert!(matches!(res, Err(Error::MissingVariable(name, _)) if name == "team"));
+    }
+
+    #[test]
+    fn it_syncs_with_upstream() {
+        let root = test_dir("it_syncs_with_upstream");
+        let (base, upstream, dest) = (root.join("base"), root.join("upstream"), root.join("dest"));
+
+        let ci = "name: ci\non: push\njobs:\n  test:\n    runs-on: ubuntu-22.04\n";
+        write_all(
+            &base,
+            &[
+                ("ci.yml", ci),
+                ("lint.yml", "v1\n"),
+                ("release.yml", "v1\n"),
+                ("old.yml", "v1\n"),
+                ("docs.yml", "v1\n"),
+            ],
+        );
+        write_all(
+            &upstream,
+            &[
+                ("ci.yml", &ci.replace("22.04", "24.04")),
+                ("lint.yml", "v2\n"),
+                ("release.yml", "v2\n"),
+                ("docs.yml", "v1\n"),
+                ("new.yml", "v1\n"),
+                ("gone.yml", "v2\n"),
+                ("local.yml", "v2\n"),
+            ],
+        );
+        write_all(
+            &dest,
+            &[
+                (
+                    "ci.yml",
+                    &ci.replace("on: push", "on: [push, pull_request]"),
+                ),
+                ("lint.yml", "v1\n"),
+                ("release.yml", "mine\n"),
+                ("old.yml", "v1\n"),
+                ("docs.yml", "mine\n"),
+                ("local.yml", "mine\n"),
+            ],
+        );
+        write_all(&base, &[("gone.yml", "v1\n")]);
+        let locked: BTreeMap<String, String> = [
+            "ci.yml",
+            "lint.yml",
+            "release.yml",
+            "old.yml",
+            "docs.yml",
+            "gone.yml",
+        ]
+        .iter()
+        .map(|p| {
+            (
+                p.to_string(),
+                lockfile::hash(&fs::read(base.join(p)).unwrap()),
+            )
+        })
+        .collect();
+
+        let report = sync(&upstream, Some(&base), &dest, &locked).unwrap();
+
+        assert_eq!(
+            report.files,
+            vec![
+                (dest.join("ci.yml"), Synced::Merged),
+                (dest.join("docs.yml"), Synced::Kept),
+                (dest.join("lint.yml"), Synced::Updated),
+                (dest.join("local.yml"), Synced::Kept),
+                (dest.join("new.yml"), Synced::Added),
+                (dest.join("old.yml"), Synced::Removed),
+                (dest.join("release.yml"), Synced::Conflict),
+            ]
+        );
+        let read = |p: &str| fs::read_to_string(dest.join(p)).unwrap();
+        assert_eq!(
+            read("ci.yml"),
+            "name: ci\non: [push, pull_request]\njobs:\n  test:\n    runs-on: ubuntu-24.04\n"
+        );
+        assert!(read("release.yml").contains("<<<<<<<"));
+        assert_eq!(read("lint.yml"), "v2\n");
+        assert!(!dest.join("old.yml").exists());
+        assert_eq!(report.hashes["release.yml"], lockfile::hash(b"v2\n"));
+        assert!(!report.hashes.contains_key("old.yml"));
+        assert_eq!(read("local.yml"), "mine\n");
+        assert!(!report.hashes.contains_key("local.yml"));
+        assert!(!dest.join("gone.yml").exists());
+        assert_eq!(report.hashes["gone.yml"], lockfile::hash(b"v2\n"));
+
+        fs::remove_dir_all(root).unwrap();
+    }
+}
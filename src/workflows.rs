@@ -0,0 +1,146 @@
+// Finds the workflow templates an organization shares from its .github repo
+use crate::errors;
+use errors::{AppResult, Error};
+use serde::Deserialize;
+use std::fs;
+use std::path::Path;
+
+/// Where an organization's .github repo keeps its workflow templates.
+pub const TEMPLATES_DIR: &str = "workflow-templates";
+
+/// Metadata GitHub reads from `<template>.properties.json`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Properties {
+    pub name: Option<String>,
+    pub description: Option<String>,
+    #[serde(default)]
+    pub categories: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct WorkflowTemplate {
+    /// File name without the extension, used to pick the template.
+    pub id: String,
+    pub file_name: String,
+    pub properties: Properties,
+}
+
+/// Lists the templates under `workflow-templates` in the fetched repo.
+pub fn find_templates(source: &Path) -> AppResult<Vec<WorkflowTemplate>> {
+    let dir = source.join(TEMPLATES_DIR);
+    if !dir.is_dir() {
+        return Ok(Vec::new());
+    }
+
+    let mut templates = Vec::new();
+    for entry in fs::read_dir(&dir)? {
+        let file_name = entry?.file_name().to_string_lossy().into_owned();
+        let id = match file_name
+            .strip_suffix(".yml")
+            .or_else(|| file_name.strip_suffix(".yaml"))
+        {
+            Some(id) => id.to_owned(),
+            None => continue,
+        };
+
+        let properties_file = dir.join(format!("{}.properties.json", id));
+        let properties = if properties_file.is_file() {
+            serde_json::from_str(&fs::read_to_string(&properties_file)?).map_err(|e| {
+                Error::BadProperties(properties_file.display().to_string(), e.to_string())
+            })?
+        } else {
+            Properties::default()
+        };
+
+        templates.push(WorkflowTemplate {
+            id,
+            file_name,
+            properties,
+        });
+    }
+    templates.sort_by(|a, b| a.id.cmp(&b.id));
+
+    Ok(templates)
+}
+
+/// Picks a template by its id.
+pub fn find_template(source: &Path, id: &str) -> AppResult<WorkflowTemplate> {
+    let templates = find_templates(source)?;
+    let ids: Vec<String> = templates.iter().map(|t| t.id.clone()).collect();
+
+    templates
+        .into_iter()
+        .find(|t| t.id == id)
+        .ok_or_else(|| Error::UnknownTemplate(id.into(), ids))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::directories::test_dir;
+
+    #[test]
+    fn it_finds_templates_with_properties() {
+        let source = test_dir("it_finds_templates");
+        let dir = source.join(TEMPLATES_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("node.yml"), "on: push").unwrap();
+        fs::write(
+            dir.join("node.properties.json"),
+            r#"{"name": "Node CI", "description": "Tests Node", "iconName": "node", "categories": ["JavaScript"]}"#,
+        )
+        .unwrap();
+        fs::write(dir.join("deploy.yaml"), "on: push").unwrap();
+        fs::write(dir.join("node.svg"), "<svg/>").unwrap();
+
+        let templates = find_templates(&source).unwrap();
+        let ids: Vec<&str> = templates.iter().map(|t| t.id.as_str()).collect();
+        assert_eq!(ids, vec!["deploy", "node"]);
+        assert_eq!(templates[1].properties.name.as_deref(), Some("Node CI"));
+        assert_eq!(templates[1].properties.categories, vec!["JavaScript"]);
+        assert!(templates[0].properties.name.is_none());
+
+        assert_eq!(
+            find_template(&source, "deploy").unwrap().file_name,
+            "deploy.yaml"
+        );
+        assert!(matches!(
+            find_template(&source, "rust"),
+            Err(Error::UnknownTemplate(..))
+        ));
+
+        fs::remove_dir_all(source).unwrap();
+    }
+
+    #[test]
+    fn it_installs_templates_into_repos_without_workflows() {
+        use crate::directories::{move_to_destination, Layout};
+
+        let base = test_dir("it_installs_templates_into_repos_without_workflows");
+        let dir = base.join("source").join(TEMPLATES_DIR);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("node.yml"), "on: push").unwrap();
+        fs::create_dir_all(base.join("repo")).unwrap();
+
+        let template = find_template(&base.join("source"), "node").unwrap();
+        let dest = base.join("repo/.github/workflows");
+        move_to_destination(
+            &base.join("source").display().to_string(),
+            &dest.display().to_string(),
+            Some(format!("{}/{}", TEMPLATES_DIR, template.file_name)),
+            None,
+            None,
+            Layout::Flatten,
+            None,
+        )
+        .unwrap();
+
+        assert_eq!(
+            fs::read_to_string(dest.join("node.yml")).unwrap(),
+            "on: push"
+        );
+
+        fs::remove_dir_all(base).unwrap();
+    }
+}
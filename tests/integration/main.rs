@@ -78,6 +78,29 @@ fn it_previews() -> Result<(), Box<dyn std::error::Error>> {
     Ok(())
 }
 
+#[test]
+fn it_rejects_diff_with_preview() -> Result<(), Box<dyn std::error::Error>> {
+    binary()
+        .args(["--diff", "--preview", "owner/repo"])
+        .assert()
+        .failure()
+        .stderr(predicate::str::contains("cannot be used with"));
+
+    Ok(())
+}
+
+#[test]
+fn it_diffs_without_prompting() -> Result<(), Box<dyn std::error::Error>> {
+    binary()
+        .args(["--diff", "--on-conflict", "prompt", "bradyjoslin/sharewifi"])
+        .arg(tmpdir("it_diffs_without_prompting"))
+        .assert()
+        .success()
+        .stdout(predicate::str::contains("These files from"));
+
+    Ok(())
+}
+
 #[test]
 fn it_gets_tarball() -> Result<(), Box<dyn std::error::Error>> {
     use std::{fs, path::PathBuf};